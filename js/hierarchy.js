@@ -126,7 +126,7 @@ function register(reg) {
 
 switch (context.action) {
     case "changed": {
-        // FIXME: need to unregister previous state first, if it changed
+        // moving devices between groups is handled by the operator, unregistering them from the previous group
         register(true);
         break;
     }
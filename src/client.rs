@@ -1,18 +1,33 @@
+use crate::metrics;
+use crate::retry::RetryConfig;
+use anyhow::anyhow;
 use async_trait::async_trait;
 use drogue_bazaar::auth::openid::TokenConfig;
 use drogue_bazaar::{core::tls::ClientConfig, reqwest::ClientFactory};
 use drogue_client::core::PropagateCurrentContext;
 use drogue_client::error::{ClientError, ErrorInformation};
 use drogue_client::openid::{
-    AccessTokenProvider, NoTokenProvider, OpenIdTokenProvider, TokenInjector, TokenProvider,
+    AccessTokenProvider, Credentials, NoTokenProvider, OpenIdTokenProvider, TokenInjector,
+    TokenProvider,
 };
 use drogue_doppelgaenger_model::Thing;
-use reqwest::{IntoUrl, Method, RequestBuilder, Response, StatusCode};
+use futures::{Stream, StreamExt};
+use reqwest::{header, IntoUrl, Method, RequestBuilder, Response, StatusCode, Upgraded, Version};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
 use std::convert::Infallible;
 use std::future::Future;
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::{
+    self,
+    handshake::{client::generate_key, derive_accept_key},
+    protocol::Role,
+};
+use tokio_tungstenite::WebSocketStream;
 use tracing::instrument;
 use url::Url;
 
@@ -54,7 +69,6 @@ pub struct TwinClientBuilder {
 }
 
 impl TwinClientBuilder {
-    #[allow(unused)]
     pub fn new<U>(api: U) -> Result<Self, reqwest::Error>
     where
         U: IntoUrl,
@@ -99,6 +113,51 @@ impl TwinClientBuilder {
     }
 }
 
+/// Options for listing things.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ListOptions {
+    /// Label selector, e.g. `role=sensor,!legacy`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub labels: Option<String>,
+    /// Maximum number of things to return
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    /// Number of things to skip
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<usize>,
+}
+
+/// Scope of a subscription to thing notifications.
+#[derive(Clone, Debug)]
+pub enum Subscription {
+    /// All things of an application
+    Application(String),
+    /// A single thing
+    Thing { application: String, thing: String },
+}
+
+/// A notification about a thing.
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Notification {
+    /// The current state of the thing, when subscribing
+    Initial { thing: Box<Thing> },
+    /// The thing was changed
+    Change { thing: Box<Thing> },
+    /// The thing was deleted
+    Deleted { application: String, thing: String },
+    /// Notifications were dropped, as the receiver didn't keep up
+    Lag { lag: u64 },
+}
+
+/// Page size used when listing all things.
+const PAGE_SIZE: usize = 100;
+/// Maximum number of things fetched when listing all things.
+const MAX_THINGS: usize = 100_000;
+/// Time before the access token expires, when notification streams get renewed (at most half
+/// of the remaining lifetime).
+const TOKEN_RENEWAL_MARGIN: Duration = Duration::from_secs(30);
+
 #[derive(Clone, Debug)]
 pub struct TwinClient {
     client: reqwest::Client,
@@ -129,15 +188,24 @@ impl TwinClient {
     {
         let request = self
             .client
-            .request(method, url)
+            .request(method.clone(), url)
             .propagate_current_context()
             .inject_token(self.token_provider.as_ref())
             .await?;
 
         let request = request_handler(request);
-        let response = request.send().await?;
 
-        response_handler(response).await
+        let start = Instant::now();
+        let response = request.send().await;
+        let status = match &response {
+            Ok(response) => response.status().as_str().to_string(),
+            Err(_) => "error".to_string(),
+        };
+        metrics::TWIN_REQUESTS
+            .with_label_values(&[method.as_str(), &status])
+            .observe(start.elapsed().as_secs_f64());
+
+        response_handler(response?).await
     }
 
     fn url(&self, path: &[&str]) -> Result<Url, ClientError> {
@@ -177,6 +245,73 @@ impl TwinClient {
         .await
     }
 
+    /// List things of an application.
+    #[instrument(level = "debug", skip(self), err)]
+    pub async fn list_things(
+        &self,
+        application: &str,
+        options: &ListOptions,
+    ) -> Result<Vec<Thing>, ClientError> {
+        self.request(
+            Method::GET,
+            self.url(&["api", "v1alpha1", "things", application, "things"])?,
+            |r| r.query(options),
+            read_response::<Vec<Thing>>,
+        )
+        .await
+        .map(Option::unwrap_or_default)
+    }
+
+    /// List all things of an application, fetching all pages.
+    pub async fn list_all_things(
+        &self,
+        application: &str,
+        labels: Option<String>,
+    ) -> Result<Vec<Thing>, ClientError> {
+        let mut result: Vec<Thing> = vec![];
+        let mut previous: Option<Vec<String>> = None;
+
+        loop {
+            let page = self
+                .list_things(
+                    application,
+                    &ListOptions {
+                        labels: labels.clone(),
+                        limit: Some(PAGE_SIZE),
+                        offset: Some(result.len()),
+                    },
+                )
+                .await?;
+
+            if page.len() > PAGE_SIZE {
+                // the service ignored the limit, so this is everything from the offset on
+                result.extend(page);
+                break;
+            }
+
+            let names: Vec<_> = page.iter().map(|t| t.metadata.name.clone()).collect();
+            if !names.is_empty() && previous.as_ref() == Some(&names) {
+                return Err(ClientError::Request(
+                    "Service ignored the offset when listing things".to_string(),
+                ));
+            }
+
+            let last = page.len() < PAGE_SIZE;
+            result.extend(page);
+            if last {
+                break;
+            }
+            if result.len() >= MAX_THINGS {
+                return Err(ClientError::Request(format!(
+                    "Too many things, stopped listing after {MAX_THINGS}"
+                )));
+            }
+            previous = Some(names);
+        }
+
+        Ok(result)
+    }
+
     #[instrument(
         level = "debug",
         skip_all, err,
@@ -193,22 +328,296 @@ impl TwinClient {
         .map(|_| ())
     }
 
+    /// Apply a JSON patch (RFC 6902) to a thing.
     #[instrument(
         level = "debug",
         skip_all, err,
-        fields(application=thing.metadata.application, name=thing.metadata.name)
+        fields(application=application.as_ref(), name=thing.as_ref())
     )]
-    pub async fn update_thing(&self, thing: Thing) -> Result<(), ClientError> {
+    pub async fn patch_thing<A: AsRef<str>, T: AsRef<str>>(
+        &self,
+        application: A,
+        thing: T,
+        patch: Value,
+    ) -> Result<(), ClientError> {
         self.request(
-            Method::PUT,
-            self.url(&["api", "v1alpha1", "things"])?,
-            json(thing),
+            Method::PATCH,
+            self.url(&[
+                "api",
+                "v1alpha1",
+                "things",
+                application.as_ref(),
+                "things",
+                thing.as_ref(),
+            ])?,
+            json_patch(patch),
             update_response::<Thing>,
         )
         .await
         .map(|_| ())
     }
 
+    /// Update the reported state of a thing, with the provided values.
+    #[instrument(
+        level = "debug",
+        skip_all, err,
+        fields(application=application.as_ref(), name=thing.as_ref())
+    )]
+    pub async fn update_reported_state<A: AsRef<str>, T: AsRef<str>>(
+        &self,
+        application: A,
+        thing: T,
+        state: BTreeMap<String, Value>,
+    ) -> Result<(), ClientError> {
+        self.request(
+            Method::PUT,
+            self.url(&[
+                "api",
+                "v1alpha1",
+                "things",
+                application.as_ref(),
+                "things",
+                thing.as_ref(),
+                "reportedStates",
+            ])?,
+            json(state),
+            update_response::<Value>,
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Set the value of a desired state of a thing.
+    #[instrument(
+        level = "debug",
+        skip_all, err,
+        fields(application=application.as_ref(), name=thing.as_ref(), state=name.as_ref())
+    )]
+    pub async fn set_desired_value<A: AsRef<str>, T: AsRef<str>, N: AsRef<str>>(
+        &self,
+        application: A,
+        thing: T,
+        name: N,
+        value: Value,
+    ) -> Result<(), ClientError> {
+        self.request(
+            Method::PUT,
+            self.url(&[
+                "api",
+                "v1alpha1",
+                "things",
+                application.as_ref(),
+                "things",
+                thing.as_ref(),
+                "desiredStates",
+                name.as_ref(),
+                "value",
+            ])?,
+            json(value),
+            update_response::<Value>,
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Send a message to a thing, like `sendMessage` does in the reconciliation scripts.
+    #[instrument(
+        level = "debug",
+        skip_all, err,
+        fields(application=application.as_ref(), name=thing.as_ref())
+    )]
+    pub async fn send_message<A: AsRef<str>, T: AsRef<str>>(
+        &self,
+        application: A,
+        thing: T,
+        message: Value,
+    ) -> Result<(), ClientError> {
+        self.request(
+            Method::POST,
+            self.url(&[
+                "api",
+                "v1alpha1",
+                "things",
+                application.as_ref(),
+                "things",
+                thing.as_ref(),
+                "messages",
+            ])?,
+            json(message),
+            create_response::<Value>,
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Subscribe to changes of things.
+    ///
+    /// The stream re-connects automatically, fetching a fresh token for each connection, and
+    /// ends when it gets dropped. A re-connect starts with the initial state of the things again.
+    pub fn subscribe(&self, subscription: Subscription) -> impl Stream<Item = Notification> {
+        let (tx, rx) = mpsc::channel(100);
+
+        let client = self.clone();
+        tokio::spawn(async move {
+            client.run_subscription(subscription, tx).await;
+        });
+
+        futures::stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|notification| (notification, rx))
+        })
+    }
+
+    async fn run_subscription(&self, subscription: Subscription, tx: mpsc::Sender<Notification>) {
+        let backoff = RetryConfig::default();
+        let mut attempt = 0;
+
+        loop {
+            let result = tokio::select! {
+                result = self.forward_notifications(&subscription, &tx, &mut attempt) => result,
+                _ = tx.closed() => return,
+            };
+
+            match result {
+                Ok(()) => log::info!("Notification stream closed ({subscription:?})"),
+                Err(err) => log::warn!("Notification stream failed ({subscription:?}): {err}"),
+            }
+
+            attempt += 1;
+            tokio::select! {
+                _ = tokio::time::sleep(backoff.delay(attempt)) => {},
+                _ = tx.closed() => return,
+            }
+        }
+    }
+
+    async fn forward_notifications(
+        &self,
+        subscription: &Subscription,
+        tx: &mpsc::Sender<Notification>,
+        attempt: &mut u32,
+    ) -> anyhow::Result<()> {
+        let (mut stream, expires) = self.connect_notifications(subscription).await?;
+        log::info!("Subscribed to notifications ({subscription:?})");
+        *attempt = 0;
+
+        // reconnect before the access token expires, as the service may check it again
+        let reconnect = async {
+            match expires {
+                Some(expires) => tokio::time::sleep_until(expires).await,
+                None => futures::future::pending().await,
+            }
+        };
+        tokio::pin!(reconnect);
+
+        loop {
+            let message = tokio::select! {
+                message = stream.next() => message,
+                _ = &mut reconnect => {
+                    log::info!("Access token expires, reconnecting ({subscription:?})");
+                    stream.close(None).await.ok();
+                    break;
+                }
+            };
+
+            let Some(message) = message else {
+                break;
+            };
+
+            match message? {
+                tungstenite::Message::Text(text) => {
+                    match serde_json::from_str::<Notification>(&text) {
+                        Ok(notification) => {
+                            if tx.send(notification).await.is_err() {
+                                // receiver is gone
+                                return Ok(());
+                            }
+                        }
+                        Err(err) => log::info!("Ignoring unknown notification: {err}"),
+                    }
+                }
+                tungstenite::Message::Close(_) => break,
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Open the WebSocket for a subscription.
+    ///
+    /// This upgrades a request of the HTTP client, so that it uses the same TLS configuration
+    /// and credentials. Returns the time the connection should be renewed, due to the expiring
+    /// access token.
+    async fn connect_notifications(
+        &self,
+        subscription: &Subscription,
+    ) -> anyhow::Result<(WebSocketStream<Upgraded>, Option<tokio::time::Instant>)> {
+        let url = match subscription {
+            Subscription::Application(application) => {
+                self.url(&["api", "v1alpha1", "things", application, "notifications"])?
+            }
+            Subscription::Thing { application, thing } => self.url(&[
+                "api",
+                "v1alpha1",
+                "things",
+                application,
+                "things",
+                thing,
+                "notifications",
+            ])?,
+        };
+
+        let key = generate_key();
+        let mut request = self
+            .client
+            .get(url)
+            .version(Version::HTTP_11)
+            .header(header::CONNECTION, "Upgrade")
+            .header(header::UPGRADE, "websocket")
+            .header(header::SEC_WEBSOCKET_VERSION, "13")
+            .header(header::SEC_WEBSOCKET_KEY, &key)
+            .propagate_current_context();
+
+        let mut expires = None;
+        if let Some(credentials) = self.token_provider.provide_access_token().await? {
+            let value = match credentials {
+                Credentials::Bearer(token) => {
+                    expires = token_expiration(&token).map(|expiration| {
+                        let margin = TOKEN_RENEWAL_MARGIN.min(expiration / 2);
+                        tokio::time::Instant::now() + (expiration - margin)
+                    });
+                    format!("Bearer {token}")
+                }
+                Credentials::Basic(user, password) => format!(
+                    "Basic {}",
+                    base64::encode(format!("{user}:{}", password.unwrap_or_default()))
+                ),
+            };
+            request = request.header(header::AUTHORIZATION, value);
+        }
+
+        let response = request.send().await?;
+        if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+            return Err(anyhow!(
+                "Failed to subscribe to notifications: {}",
+                response.status()
+            ));
+        }
+
+        let accept = response
+            .headers()
+            .get(header::SEC_WEBSOCKET_ACCEPT)
+            .and_then(|value| value.to_str().ok());
+        if accept != Some(derive_accept_key(key.as_bytes()).as_str()) {
+            return Err(anyhow!("Invalid WebSocket handshake response"));
+        }
+
+        let upgraded = response.upgrade().await?;
+        let stream = WebSocketStream::from_raw_socket(upgraded, Role::Client, None).await;
+
+        Ok((stream, expires))
+    }
+
     #[instrument(
         level = "debug",
         skip_all, ret, err,
@@ -236,6 +645,21 @@ impl TwinClient {
     }
 }
 
+/// Time until a JWT access token expires, based on its `exp` claim.
+fn token_expiration(token: &str) -> Option<Duration> {
+    #[derive(serde::Deserialize)]
+    struct Claims {
+        exp: u64,
+    }
+
+    let payload = token.split('.').nth(1)?;
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+    let claims: Claims = serde_json::from_slice(&payload).ok()?;
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+    Some(Duration::from_secs(claims.exp).saturating_sub(now))
+}
+
 #[inline]
 fn empty(request: RequestBuilder) -> RequestBuilder {
     request
@@ -245,6 +669,13 @@ fn json<S: Serialize>(payload: S) -> impl FnOnce(RequestBuilder) -> RequestBuild
     move |r| r.json(&payload)
 }
 
+fn json_patch(patch: Value) -> impl FnOnce(RequestBuilder) -> RequestBuilder {
+    move |r| {
+        r.header(header::CONTENT_TYPE, "application/json-patch+json")
+            .body(patch.to_string())
+    }
+}
+
 async fn create_response<T: DeserializeOwned>(
     response: Response,
 ) -> Result<Option<T>, ClientError> {
@@ -293,3 +724,40 @@ async fn default_response<T>(response: Response) -> Result<T, ClientError> {
         Err(_) => Err(ClientError::Response(code)),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn token(claims: &str) -> String {
+        format!(
+            "e30.{}.c2lnbmF0dXJl",
+            base64::encode_config(claims, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    #[test]
+    fn test_token_expiration() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let expiration = token_expiration(&token(&format!(
+            r#"{{"exp":{},"sub":"foo"}}"#,
+            now.as_secs() + 300
+        )))
+        .unwrap();
+        assert!(expiration <= Duration::from_secs(300));
+        assert!(expiration > Duration::from_secs(290));
+    }
+
+    #[test]
+    fn test_token_expired() {
+        let expiration = token_expiration(&token(r#"{"exp":1000}"#));
+        assert_eq!(expiration, Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_token_without_expiration() {
+        assert_eq!(token_expiration(&token(r#"{"sub":"foo"}"#)), None);
+        assert_eq!(token_expiration("opaque-token"), None);
+        assert_eq!(token_expiration("a.%%%.c"), None);
+    }
+}
@@ -1,22 +1,333 @@
+use crate::diff::apply_merge_patch;
+use crate::naming::NamePattern;
+use crate::selector::Selector;
+use crate::variables::Variables;
+use anyhow::Context;
 use drogue_doppelgaenger_model::SyntheticType;
 use indexmap::IndexMap;
 use serde::de::{Error, MapAccess};
 use serde::{de, Deserialize, Deserializer};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
 use std::fmt::Formatter;
 use std::fs;
 use std::fs::File;
 use std::path::Path;
 use std::time::Duration;
 
+/// Sections of a thing spec, which the top-level sensor thing is made of.
+const SENSOR_SECTIONS: [&str; 3] = ["annotations", "reconciliation", "synthetics"];
+
+/// Templates, selected by the labels of a device.
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Templates {
+    pub templates: Vec<SelectableTemplate>,
+    /// Template for devices matching no selector, which are skipped otherwise
+    #[serde(default)]
+    pub default: Option<String>,
+    /// The things of the groups, shared by the devices of all templates
+    #[serde(default)]
+    pub group: ThingSpec,
+}
+
+impl Templates {
+    /// A revision of the group template, which changes when its content changes.
+    pub fn group_revision(&self) -> String {
+        self.group.revision()
+    }
+}
+
+/// A single template for all devices, with the things of the groups.
+#[derive(Clone, Debug, serde::Deserialize)]
+struct SingleTemplate {
+    #[serde(flatten)]
+    template: ThingTemplate,
+    #[serde(default)]
+    group: ThingSpec,
+}
+
+/// A template, applied to the devices matching its selector.
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SelectableTemplate {
+    pub name: String,
+    /// Selector for the labels of a device
+    #[serde(default)]
+    pub selector: Selector,
+    /// Templates with a higher priority are checked first
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(flatten)]
+    pub template: ThingTemplate,
+    #[serde(skip)]
+    pub revision: String,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ThingTemplate {
+    /// The sensor thing, defined on the top level for compatibility
+    #[serde(flatten)]
+    pub sensor: ThingSpec,
+    /// The things of the device, besides the device thing
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub things: Vec<ThingRole>,
+    /// The device thing
+    #[serde(default, skip_serializing_if = "ThingSpec::is_empty")]
+    pub device: ThingSpec,
+}
+
+/// A role of things of a device.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ThingRole {
+    /// Name of the role
+    pub role: String,
+    /// Name of the things, e.g. `{device}/{channel}`
+    pub name: NamePattern,
+    /// Channels to create things for, unless provided by the device
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub channels: Vec<String>,
+    #[serde(flatten)]
+    pub spec: ThingSpec,
+}
+
+/// The managed content of a single thing.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ThingSpec {
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub annotations: IndexMap<String, String>,
     #[serde(default, skip_serializing_if = "Reconciliation::is_empty")]
     pub reconciliation: Reconciliation,
     #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
     pub synthetics: IndexMap<String, Synthetic>,
 }
 
+impl ThingSpec {
+    pub fn is_empty(&self) -> bool {
+        self.annotations.is_empty() && self.reconciliation.is_empty() && self.synthetics.is_empty()
+    }
+
+    /// A revision of the spec, which changes when its content changes.
+    pub fn revision(&self) -> String {
+        revision(self)
+    }
+
+    /// Render the variables of the synthetics and the reconciliation code.
+    pub fn render(&self, variables: &Variables) -> anyhow::Result<ThingSpec> {
+        let mut spec = self.clone();
+
+        for (name, synthetic) in &mut spec.synthetics {
+            let result = match synthetic {
+                Synthetic::JavaScript(source) => source.render(variables),
+                Synthetic::Alias(alias) => variables.render(alias).map(|a| *alias = a),
+            };
+            result.with_context(|| format!("failed to render synthetic '{name}'"))?;
+        }
+
+        let reconciliation = &mut spec.reconciliation;
+        let code = reconciliation
+            .changed
+            .iter_mut()
+            .map(|(name, code)| (format!("changed '{name}'"), code))
+            .chain(
+                reconciliation
+                    .deleting
+                    .iter_mut()
+                    .map(|(name, code)| (format!("deleting '{name}'"), code)),
+            )
+            .chain(
+                reconciliation
+                    .timers
+                    .iter_mut()
+                    .map(|(name, timer)| (format!("timer '{name}'"), &mut timer.code)),
+            );
+        for (name, code) in code {
+            let Code::JavaScript(source) = code;
+            source
+                .render(variables)
+                .with_context(|| format!("failed to render {name}"))?;
+        }
+
+        Ok(spec)
+    }
+}
+
+impl ThingTemplate {
+    /// All roles of things of a device, including the legacy sensor thing.
+    pub fn roles(&self) -> Vec<ThingRole> {
+        let mut roles = vec![];
+        if !self.sensor.is_empty() {
+            roles.push(ThingRole {
+                role: "sensor".to_string(),
+                name: NamePattern::try_from("{device}/sensor".to_string())
+                    .expect("valid name pattern"),
+                channels: vec![],
+                spec: self.sensor.clone(),
+            });
+        }
+        roles.extend(self.things.iter().cloned());
+        roles
+    }
+
+    /// A revision of the template, which changes when the content of the template changes.
+    pub fn revision(&self) -> String {
+        revision(self)
+    }
+
+    /// Check that the roles, and the things they create, have distinct names.
+    ///
+    /// Devices may provide other channels, so the things are checked with the channels of the
+    /// template only.
+    pub fn check(&self) -> anyhow::Result<()> {
+        let mut roles = HashSet::new();
+        let mut things = HashMap::new();
+        for role in self.roles() {
+            if !roles.insert(role.role.clone()) {
+                anyhow::bail!("Duplicate role: {}", role.role);
+            }
+
+            let names = if role.name.has_channel() {
+                role.channels
+                    .iter()
+                    .map(|channel| role.name.render("{device}", Some(channel)))
+                    .collect()
+            } else {
+                vec![role.name.render("{device}", None)]
+            };
+            for name in names {
+                if let Some(other) = things.insert(name.clone(), role.role.clone()) {
+                    anyhow::bail!(
+                        "Roles '{other}' and '{}' both create the thing {name}",
+                        role.role
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Render the variables of the things of a device.
+    pub fn render(&self, variables: &Variables) -> anyhow::Result<ThingTemplate> {
+        Ok(ThingTemplate {
+            sensor: self.sensor.render(variables).context("sensor thing")?,
+            things: self
+                .things
+                .iter()
+                .map(|role| {
+                    Ok(ThingRole {
+                        spec: role
+                            .spec
+                            .render(variables)
+                            .with_context(|| format!("role '{}'", role.role))?,
+                        ..role.clone()
+                    })
+                })
+                .collect::<anyhow::Result<_>>()?,
+            device: self.device.render(variables).context("device thing")?,
+        })
+    }
+
+    /// Create a template with overrides deep-merged on top of it.
+    ///
+    /// Objects are merged, all other values (including lists) replace the original ones, and
+    /// `null` removes them. The things of the template are overridden by their role, e.g.
+    /// `{"things": {"channel": {...}}}`, and the top-level sensor thing only if the template
+    /// has one. Overrides must not load sources from files.
+    pub fn with_overrides(&self, overrides: &Value) -> anyhow::Result<ThingTemplate> {
+        check_sources(overrides)?;
+
+        let mut overrides = match overrides {
+            Value::Object(overrides) => overrides.clone(),
+            _ => anyhow::bail!("Overrides must be an object"),
+        };
+        let things = overrides.remove("things");
+
+        if self.sensor.is_empty() {
+            if let Some(section) = SENSOR_SECTIONS
+                .iter()
+                .find(|section| overrides.get(**section).is_some_and(|v| !v.is_null()))
+            {
+                anyhow::bail!(
+                    "The template has no top-level sensor thing, override '{section}' of a role through 'things' instead"
+                );
+            }
+        }
+
+        let mut template = serde_json::to_value(ThingTemplate {
+            things: vec![],
+            ..self.clone()
+        })?;
+        apply_merge_patch(&mut template, &Value::Object(overrides));
+        let mut template: ThingTemplate = serde_json::from_value(template)?;
+
+        template.things = match things {
+            None => self.things.clone(),
+            Some(Value::Null) => vec![],
+            Some(Value::Object(roles)) => {
+                let mut things = self.things.clone();
+                for (name, overrides) in roles {
+                    let index = things
+                        .iter()
+                        .position(|role| role.role == name)
+                        .ok_or_else(|| anyhow::anyhow!("Unknown role: {name}"))?;
+                    if overrides.is_null() {
+                        things.remove(index);
+                        continue;
+                    }
+
+                    let mut role = serde_json::to_value(&things[index])?;
+                    apply_merge_patch(&mut role, &overrides);
+                    let role: ThingRole = serde_json::from_value(role)
+                        .with_context(|| format!("invalid overrides of role '{name}'"))?;
+                    if role.role != name {
+                        anyhow::bail!("Role '{name}' must not be renamed");
+                    }
+                    things[index] = role;
+                }
+                things
+            }
+            Some(_) => anyhow::bail!("Overrides of things must be keyed by their role"),
+        };
+
+        template.check()?;
+        Ok(template)
+    }
+}
+
+/// A revision of some content, as a SHA-256 hash of its JSON serialization.
+///
+/// The revision is stable across restarts and builds of the operator, as long as the
+/// serialization doesn't change.
+pub fn revision<T: serde::Serialize>(value: &T) -> String {
+    let json = serde_json::to_vec(value).unwrap_or_default();
+    format!("{:x}", Sha256::digest(json))
+}
+
+/// Reject external sources, which would allow reading files of the operator.
+fn check_sources(value: &Value) -> anyhow::Result<()> {
+    match value {
+        Value::Object(fields) => {
+            for (key, value) in fields {
+                if key == "javaScript" && value.is_object() {
+                    anyhow::bail!("Loading sources from files is not allowed in overrides");
+                }
+                check_sources(value)?;
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                check_sources(value)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
 #[serde(rename_all = "camelCase")]
 pub enum Synthetic {
@@ -37,6 +348,13 @@ impl From<Synthetic> for SyntheticType {
 #[serde(rename_all = "camelCase", transparent)]
 pub struct Source(String);
 
+impl Source {
+    fn render(&mut self, variables: &Variables) -> anyhow::Result<()> {
+        self.0 = variables.render_script(&self.0)?;
+        Ok(())
+    }
+}
+
 impl<'de> Deserialize<'de> for Source {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -120,10 +438,340 @@ pub struct Reconciliation {
 
 impl Reconciliation {
     pub fn is_empty(&self) -> bool {
-        self.changed.is_empty() && self.deleting.is_empty()
+        self.changed.is_empty() && self.deleting.is_empty() && self.timers.is_empty()
     }
 }
 
-pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<ThingTemplate> {
-    Ok(serde_yaml::from_reader(File::open(path)?)?)
+/// Load the templates.
+///
+/// A file without a list of templates is treated as a single template for all devices.
+pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Templates> {
+    parse(serde_yaml::from_reader(File::open(path)?)?)
+}
+
+fn parse(value: serde_yaml::Value) -> anyhow::Result<Templates> {
+    let mut templates = if value.get("templates").is_some() {
+        serde_yaml::from_value(value)?
+    } else {
+        let SingleTemplate { template, group } = serde_yaml::from_value(value)?;
+        Templates {
+            templates: vec![SelectableTemplate {
+                name: "default".to_string(),
+                selector: Default::default(),
+                priority: 0,
+                template,
+                revision: Default::default(),
+            }],
+            default: None,
+            group,
+        }
+    };
+
+    // the sort is stable, so templates of the same priority keep their order
+    templates
+        .templates
+        .sort_by_key(|template| std::cmp::Reverse(template.priority));
+    for template in &mut templates.templates {
+        template
+            .template
+            .check()
+            .with_context(|| format!("invalid template '{}'", template.name))?;
+        template.revision = template.template.revision();
+    }
+
+    if let Some(default) = &templates.default {
+        if !templates.templates.iter().any(|t| &t.name == default) {
+            anyhow::bail!("Unknown default template: {default}");
+        }
+    }
+
+    Ok(templates)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn template(value: Value) -> ThingTemplate {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_roles_sensor_timers_only() {
+        let template = template(json!({
+            "reconciliation": {
+                "timers": {
+                    "tick": {"code": {"javaScript": "tick();"}, "period": "1m"},
+                },
+            },
+        }));
+
+        let roles = template.roles();
+        assert_eq!(roles.len(), 1);
+        assert_eq!(roles[0].role, "sensor");
+        assert_eq!(roles[0].spec.reconciliation.timers.len(), 1);
+    }
+
+    #[test]
+    fn test_roles_empty_sensor() {
+        let template = template(json!({
+            "things": [
+                {"role": "channel", "name": "{device}/{channel}", "channels": ["a", "b"]},
+            ],
+        }));
+
+        let roles = template.roles();
+        assert_eq!(roles.len(), 1);
+        assert_eq!(roles[0].role, "channel");
+        assert_eq!(roles[0].channels, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_roles_sensor_first() {
+        let template = template(json!({
+            "annotations": {"foo": "bar"},
+            "things": [
+                {"role": "channel", "name": "{device}/{channel}"},
+            ],
+        }));
+
+        let roles: Vec<_> = template.roles().into_iter().map(|r| r.role).collect();
+        assert_eq!(roles, vec!["sensor", "channel"]);
+    }
+
+    #[test]
+    fn test_timers_round_trip() {
+        let template = template(json!({
+            "reconciliation": {
+                "timers": {
+                    "tick": {"code": {"javaScript": "tick();"}, "period": "1m"},
+                },
+            },
+            "device": {
+                "reconciliation": {
+                    "timers": {
+                        "tock": {"code": {"javaScript": "tock();"}, "period": "5s"},
+                    },
+                },
+            },
+        }));
+
+        let value = serde_json::to_value(&template).unwrap();
+        assert_eq!(
+            value.pointer("/reconciliation/timers/tick/period"),
+            Some(&json!("1m"))
+        );
+        assert_eq!(
+            serde_json::from_value::<ThingTemplate>(value).unwrap(),
+            template
+        );
+    }
+
+    #[test]
+    fn test_revision() {
+        let a = template(json!({"annotations": {"foo": "bar"}}));
+        let b = template(json!({"annotations": {"foo": "baz"}}));
+        let timers = template(json!({
+            "annotations": {"foo": "bar"},
+            "reconciliation": {
+                "timers": {
+                    "tick": {"code": {"javaScript": "tick();"}, "period": "1m"},
+                },
+            },
+        }));
+
+        assert_eq!(a.revision(), a.clone().revision());
+        assert_ne!(a.revision(), b.revision());
+        assert_ne!(a.revision(), timers.revision());
+        // stable across restarts and builds
+        assert_eq!(
+            revision(&json!({"foo": "bar"})),
+            "7a38bf81f383f69433ad6e900d35b3e2385593f76a7b7ab5d4355b8ba41ee24b"
+        );
+    }
+
+    #[test]
+    fn test_parse_single() {
+        let templates = parse(
+            serde_yaml::from_str(
+                r#"
+annotations:
+  foo: bar
+group:
+  annotations:
+    baz: qux
+"#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(templates.templates.len(), 1);
+        let template = &templates.templates[0].template;
+        assert_eq!(template.sensor.annotations.get("foo").unwrap(), "bar");
+        assert_eq!(templates.group.annotations.get("baz").unwrap(), "qux");
+    }
+
+    #[test]
+    fn test_parse_multiple() {
+        let templates = parse(
+            serde_yaml::from_str(
+                r#"
+templates:
+  - name: a
+    annotations:
+      foo: a
+  - name: b
+    priority: 10
+    annotations:
+      foo: b
+group:
+  annotations:
+    baz: qux
+"#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+        let names: Vec<_> = templates.templates.iter().map(|t| &t.name).collect();
+        assert_eq!(names, vec!["b", "a"]);
+        assert_eq!(templates.group.annotations.get("baz").unwrap(), "qux");
+    }
+
+    #[test]
+    fn test_group_revision() {
+        let parse = |yaml: &str| parse(serde_yaml::from_str(yaml).unwrap()).unwrap();
+
+        let a = parse("annotations: {foo: a}\ngroup: {annotations: {baz: qux}}");
+        let b = parse("annotations: {foo: b}\ngroup: {annotations: {baz: qux}}");
+        let c = parse("annotations: {foo: a}\ngroup: {annotations: {baz: other}}");
+
+        // only depends on the group template
+        assert_eq!(a.group_revision(), b.group_revision());
+        assert_ne!(a.group_revision(), c.group_revision());
+    }
+
+    #[test]
+    fn test_check() {
+        let valid = template(json!({
+            "annotations": {"foo": "bar"},
+            "things": [
+                {"role": "channel", "name": "{device}/{channel}", "channels": ["a", "b"]},
+                {"role": "state", "name": "{device}/state"},
+            ],
+        }));
+        assert!(valid.check().is_ok());
+
+        // the legacy sensor thing collides with the sensor channel
+        let sensor = template(json!({
+            "annotations": {"foo": "bar"},
+            "things": [
+                {"role": "channel", "name": "{device}/{channel}", "channels": ["sensor"]},
+            ],
+        }));
+        assert!(sensor.check().is_err());
+
+        let roles = template(json!({
+            "things": [
+                {"role": "channel", "name": "{device}/a"},
+                {"role": "channel", "name": "{device}/b"},
+            ],
+        }));
+        assert!(roles.check().is_err());
+
+        let yaml = "annotations: {foo: bar}\nthings: [{role: s, name: '{device}/sensor'}]";
+        assert!(parse(serde_yaml::from_str(yaml).unwrap()).is_err());
+    }
+
+    fn shipped() -> ThingTemplate {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/template.yaml");
+        load(path).unwrap().templates.remove(0).template
+    }
+
+    #[test]
+    fn test_overrides_role() {
+        let template = shipped();
+        let overridden = template
+            .with_overrides(&json!({
+                "things": {
+                    "channel": {
+                        "synthetics": {"extra": {"javaScript": "extra();"}},
+                    },
+                },
+            }))
+            .unwrap();
+
+        let roles = overridden.roles();
+        assert_eq!(roles.len(), 1);
+        let synthetics = &roles[0].spec.synthetics;
+        // the sources loaded from files are kept
+        assert_eq!(
+            synthetics.get_index(0),
+            template.things[0].spec.synthetics.get_index(0)
+        );
+        assert_eq!(
+            synthetics.get("extra"),
+            Some(&Synthetic::JavaScript(Source("extra();".to_string())))
+        );
+        assert_eq!(overridden.device, template.device);
+    }
+
+    #[test]
+    fn test_overrides_role_remove() {
+        let overridden = shipped()
+            .with_overrides(&json!({
+                "things": {"channel": {"synthetics": {"noise": null}, "channels": ["a", "b"]}},
+            }))
+            .unwrap();
+
+        assert_eq!(overridden.things[0].channels, vec!["a", "b"]);
+        assert!(!overridden.things[0].spec.synthetics.contains_key("noise"));
+        assert!(overridden.things[0]
+            .spec
+            .synthetics
+            .contains_key("temperature"));
+
+        let overridden = shipped()
+            .with_overrides(&json!({"things": {"channel": null}}))
+            .unwrap();
+        assert!(overridden.roles().is_empty());
+    }
+
+    #[test]
+    fn test_overrides_invalid() {
+        let template = shipped();
+        for overrides in [
+            // the shipped template has no top-level sensor thing
+            json!({"synthetics": {"extra": {"javaScript": "extra();"}}}),
+            json!({"annotations": {"foo": "bar"}}),
+            json!({"things": [{"role": "channel", "name": "{device}/{channel}"}]}),
+            json!({"things": {"unknown": {"annotations": {"foo": "bar"}}}}),
+            json!({"things": {"channel": {"role": "other"}}}),
+            json!({"things": {"channel": {"synthetics": {"extra": {"javaScript": {"path": "/etc/passwd"}}}}}}),
+            json!([]),
+        ] {
+            assert!(template.with_overrides(&overrides).is_err(), "{overrides}");
+        }
+    }
+
+    #[test]
+    fn test_overrides_sensor() {
+        let template = template(json!({
+            "annotations": {"foo": "bar"},
+            "things": [{"role": "channel", "name": "{device}/{channel}", "channels": ["a"]}],
+        }));
+
+        let overridden = template
+            .with_overrides(&json!({"annotations": {"foo": "baz"}}))
+            .unwrap();
+        assert_eq!(overridden.sensor.annotations.get("foo").unwrap(), "baz");
+        assert_eq!(overridden.things, template.things);
+
+        // overrides must not create the same thing twice
+        assert!(template
+            .with_overrides(&json!({"things": {"channel": {"channels": ["sensor"]}}}))
+            .is_err());
+    }
 }
@@ -0,0 +1,195 @@
+use crate::metrics;
+use crate::retry::RetryConfig;
+use paho_mqtt as mqtt;
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+
+/// Backoff for reconnecting to the MQTT endpoint.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct ReconnectConfig {
+    /// Delay before the first reconnect attempt
+    #[serde(default = "default::initial_delay", with = "humantime_serde")]
+    pub initial_delay: Duration,
+
+    /// Maximum delay between two reconnect attempts
+    #[serde(default = "default::max_delay", with = "humantime_serde")]
+    pub max_delay: Duration,
+}
+
+mod default {
+    use std::time::Duration;
+
+    pub const fn initial_delay() -> Duration {
+        Duration::from_millis(100)
+    }
+
+    pub const fn max_delay() -> Duration {
+        Duration::from_secs(10)
+    }
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            initial_delay: default::initial_delay(),
+            max_delay: default::max_delay(),
+        }
+    }
+}
+
+/// A supervised MQTT connection.
+///
+/// Tracks the connection state and the subscribed topics, so that they can be restored after
+/// re-connecting.
+pub struct Connection {
+    client: mqtt::AsyncClient,
+    stream: Option<mqtt::AsyncReceiver<Option<mqtt::Message>>>,
+    state: Arc<watch::Sender<bool>>,
+    topics: Mutex<BTreeSet<String>>,
+    backoff: RetryConfig,
+}
+
+impl Connection {
+    pub fn new(mut client: mqtt::AsyncClient, config: ReconnectConfig) -> Self {
+        // start the stream before connecting, so that we don't miss messages of a persistent session
+        let stream = Some(client.get_stream(100));
+
+        let (state, _) = watch::channel(false);
+        let state = Arc::new(state);
+
+        {
+            let state = state.clone();
+            client.set_connected_callback(move |_| {
+                log::info!("Connected");
+                metrics::MQTT_CONNECTED.set(1);
+                state.send_replace(true);
+            });
+        }
+        {
+            let state = state.clone();
+            client.set_disconnected_callback(move |_, _, _| {
+                log::info!("Disconnected");
+                metrics::MQTT_CONNECTED.set(0);
+                state.send_replace(false);
+            });
+        }
+        {
+            let state = state.clone();
+            client.set_connection_lost_callback(move |_| {
+                log::info!("Connection lost");
+                metrics::MQTT_CONNECTED.set(0);
+                state.send_replace(false);
+            });
+        }
+
+        Self {
+            client,
+            stream,
+            state,
+            topics: Default::default(),
+            backoff: RetryConfig {
+                initial_delay: config.initial_delay,
+                max_delay: config.max_delay,
+                ..Default::default()
+            },
+        }
+    }
+
+    pub fn client(&self) -> &mqtt::AsyncClient {
+        &self.client
+    }
+
+    /// Take the stream of received messages, can only be called once.
+    pub fn take_stream(&mut self) -> Option<mqtt::AsyncReceiver<Option<mqtt::Message>>> {
+        self.stream.take()
+    }
+
+    /// Watch the connection state, `true` meaning connected.
+    pub fn state(&self) -> watch::Receiver<bool> {
+        self.state.subscribe()
+    }
+
+    pub async fn connect(&self, options: mqtt::ConnectOptions) -> mqtt::Result<()> {
+        self.client.connect(options).await?;
+        self.state.send_replace(true);
+        Ok(())
+    }
+
+    pub async fn disconnect(&self) -> mqtt::Result<()> {
+        self.client.disconnect(None).await?;
+        Ok(())
+    }
+
+    pub async fn subscribe(&self, topic: String) -> mqtt::Result<()> {
+        self.client.subscribe(&topic, 1).await?;
+        self.topics.lock().unwrap().insert(topic);
+        Ok(())
+    }
+
+    pub async fn unsubscribe(&self, topic: String) -> mqtt::Result<()> {
+        self.client.unsubscribe(&topic).await?;
+        self.topics.lock().unwrap().remove(&topic);
+        Ok(())
+    }
+
+    async fn resubscribe(&self) -> mqtt::Result<()> {
+        let topics: Vec<_> = self.topics.lock().unwrap().iter().cloned().collect();
+        for topic in topics {
+            log::info!("Re-subscribing to: {topic}");
+            self.client.subscribe(topic, 1).await?;
+        }
+        Ok(())
+    }
+
+    /// Supervise the connection, re-connecting when it gets lost.
+    ///
+    /// After a successful re-connect, the topics are subscribed to again, and `resync` gets
+    /// notified, to cover events which got missed while being disconnected.
+    pub async fn supervise(&self, resync: &Notify) {
+        let mut state = self.state();
+
+        loop {
+            // wait for the connection to get lost
+            loop {
+                let connected = *state.borrow_and_update();
+                if !connected {
+                    break;
+                }
+                if state.changed().await.is_err() {
+                    return;
+                }
+            }
+
+            let mut attempt = 0;
+            loop {
+                attempt += 1;
+                log::info!("Re-connecting to MQTT endpoint (attempt {attempt})");
+
+                let result = if self.client.is_connected() {
+                    // only the re-subscribe failed last time
+                    self.resubscribe().await
+                } else {
+                    match self.client.reconnect().await {
+                        Ok(_) => self.resubscribe().await,
+                        Err(err) => Err(err),
+                    }
+                };
+
+                match result {
+                    Ok(()) => break,
+                    Err(err) => {
+                        let delay = self.backoff.delay(attempt);
+                        log::warn!("Failed to re-connect, retrying in {delay:?}: {err}");
+                        tokio::time::sleep(delay).await;
+                    }
+                }
+            }
+
+            log::info!("Re-connected to MQTT endpoint");
+            self.state.send_replace(true);
+            resync.notify_one();
+        }
+    }
+}
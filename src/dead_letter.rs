@@ -0,0 +1,98 @@
+use chrono::{DateTime, Utc};
+use paho_mqtt as mqtt;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+
+/// Destinations for events which could not be processed.
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct DeadLetterConfig {
+    /// MQTT topic to publish failed events to
+    #[serde(default)]
+    pub topic: Option<String>,
+
+    /// File to append failed events to, one JSON record per line
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+}
+
+/// A record of an event, or device, which failed processing.
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeadLetter {
+    pub timestamp: DateTime<Utc>,
+    pub reason: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub application: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    /// Original payload, base64 encoded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload: Option<String>,
+}
+
+impl DeadLetter {
+    pub fn new<R: Into<String>>(reason: R) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            reason: reason.into(),
+            topic: None,
+            application: None,
+            device: None,
+            payload: None,
+        }
+    }
+}
+
+pub struct DeadLetterSink {
+    config: DeadLetterConfig,
+    client: mqtt::AsyncClient,
+}
+
+impl DeadLetterSink {
+    pub fn new(config: DeadLetterConfig, client: mqtt::AsyncClient) -> Self {
+        Self { config, client }
+    }
+
+    /// Send a dead letter to all configured destinations.
+    ///
+    /// Failing to do so is only logged, as there isn't much else we can do.
+    pub async fn send(&self, letter: DeadLetter) {
+        if self.config.topic.is_none() && self.config.path.is_none() {
+            return;
+        }
+
+        let mut payload = match serde_json::to_vec(&letter) {
+            Ok(payload) => payload,
+            Err(err) => {
+                log::warn!("Failed to encode dead letter: {err}");
+                return;
+            }
+        };
+
+        if let Some(topic) = &self.config.topic {
+            let message = mqtt::Message::new(topic, payload.clone(), 1);
+            if let Err(err) = self.client.publish(message).await {
+                log::warn!("Failed to publish dead letter to '{topic}': {err}");
+            }
+        }
+
+        if let Some(path) = &self.config.path {
+            payload.push(b'\n');
+            if let Err(err) = Self::append(path, &payload).await {
+                log::warn!("Failed to write dead letter to '{}': {err}", path.display());
+            }
+        }
+    }
+
+    async fn append(path: &Path, payload: &[u8]) -> std::io::Result<()> {
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        file.write_all(payload).await?;
+        file.flush().await
+    }
+}
@@ -0,0 +1,283 @@
+use serde_json::{json, Map, Value};
+use std::fmt::{Display, Formatter};
+
+/// A change between two JSON values.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    Added {
+        path: String,
+        value: Value,
+    },
+    Removed {
+        path: String,
+        value: Value,
+    },
+    Changed {
+        path: String,
+        from: Value,
+        to: Value,
+    },
+}
+
+impl Change {
+    /// The JSON pointer of the changed value.
+    pub fn path(&self) -> &str {
+        match self {
+            Self::Added { path, .. } | Self::Removed { path, .. } | Self::Changed { path, .. } => {
+                path
+            }
+        }
+    }
+
+    /// The JSON patch (RFC 6902) operation, applying this change.
+    pub fn to_patch_operation(&self) -> Value {
+        match self {
+            Self::Added { path, value } => json!({"op": "add", "path": path, "value": value}),
+            Self::Removed { path, .. } => json!({"op": "remove", "path": path}),
+            Self::Changed { path, to, .. } => json!({"op": "replace", "path": path, "value": to}),
+        }
+    }
+}
+
+/// Maximum length of values when displaying a change.
+const MAX_DISPLAY_LENGTH: usize = 80;
+
+impl Display for Change {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Added { path, value } => write!(f, "+ {path}: {}", truncate(value)),
+            Self::Removed { path, value } => write!(f, "- {path}: {}", truncate(value)),
+            Self::Changed { path, from, to } => {
+                write!(f, "~ {path}: {} -> {}", truncate(from), truncate(to))
+            }
+        }
+    }
+}
+
+/// Serialize a value for display, truncating long values (like scripts).
+fn truncate(value: &Value) -> String {
+    let value = value.to_string();
+    match value.char_indices().nth(MAX_DISPLAY_LENGTH) {
+        Some((end, _)) => format!("{}...", &value[..end]),
+        None => value,
+    }
+}
+
+/// Compare two JSON values, returning the changes by JSON pointer.
+///
+/// Objects are compared field by field, all other values (including arrays) as a whole.
+pub fn diff(from: &Value, to: &Value) -> Vec<Change> {
+    let mut changes = vec![];
+    diff_into(String::new(), from, to, &mut changes);
+    changes
+}
+
+fn diff_into(path: String, from: &Value, to: &Value, changes: &mut Vec<Change>) {
+    match (from, to) {
+        (Value::Object(from), Value::Object(to)) => {
+            for (key, from_value) in from {
+                let path = format!("{path}/{}", escape(key));
+                match to.get(key) {
+                    Some(to_value) => diff_into(path, from_value, to_value, changes),
+                    None => changes.push(Change::Removed {
+                        path,
+                        value: from_value.clone(),
+                    }),
+                }
+            }
+            for (key, to_value) in to {
+                if !from.contains_key(key) {
+                    changes.push(Change::Added {
+                        path: format!("{path}/{}", escape(key)),
+                        value: to_value.clone(),
+                    });
+                }
+            }
+        }
+        (from, to) if from != to => changes.push(Change::Changed {
+            path,
+            from: from.clone(),
+            to: to.clone(),
+        }),
+        _ => {}
+    }
+}
+
+/// Escape a key for use in a JSON pointer.
+fn escape(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+/// Create a JSON patch (RFC 6902) from a list of changes.
+pub fn json_patch<'a>(changes: impl IntoIterator<Item = &'a Change>) -> Value {
+    Value::Array(
+        changes
+            .into_iter()
+            .map(Change::to_patch_operation)
+            .collect(),
+    )
+}
+
+/// Apply a JSON merge patch (RFC 7396) to a value.
+pub fn apply_merge_patch(target: &mut Value, patch: &Value) {
+    match patch {
+        Value::Object(patch) => {
+            if !target.is_object() {
+                *target = Value::Object(Map::new());
+            }
+            if let Value::Object(target) = target {
+                for (key, value) in patch {
+                    if value.is_null() {
+                        target.remove(key);
+                    } else {
+                        apply_merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+                    }
+                }
+            }
+        }
+        patch => *target = patch.clone(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_diff_equal() {
+        let value = json!({"a": 1, "b": {"c": [1, 2]}});
+        assert_eq!(diff(&value, &value), vec![]);
+    }
+
+    #[test]
+    fn test_diff_nested() {
+        let from = json!({"a": 1, "b": {"c": 1, "d": 2}, "e": true});
+        let to = json!({"a": 2, "b": {"c": 1, "f": null}, "e": true});
+
+        assert_eq!(
+            diff(&from, &to),
+            vec![
+                Change::Changed {
+                    path: "/a".into(),
+                    from: json!(1),
+                    to: json!(2),
+                },
+                Change::Removed {
+                    path: "/b/d".into(),
+                    value: json!(2),
+                },
+                Change::Added {
+                    path: "/b/f".into(),
+                    value: Value::Null,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff_arrays_as_whole() {
+        let from = json!({"a": [1, 2]});
+        let to = json!({"a": [1, 3]});
+
+        assert_eq!(
+            diff(&from, &to),
+            vec![Change::Changed {
+                path: "/a".into(),
+                from: json!([1, 2]),
+                to: json!([1, 3]),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_diff_type_change() {
+        let from = json!({"a": {"b": 1}});
+        let to = json!({"a": "b"});
+
+        assert_eq!(
+            diff(&from, &to),
+            vec![Change::Changed {
+                path: "/a".into(),
+                from: json!({"b": 1}),
+                to: json!("b"),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_diff_escape() {
+        let from = json!({});
+        let to = json!({"a/b": {"c~d": 1}});
+
+        assert_eq!(
+            diff(&from, &to),
+            vec![Change::Added {
+                path: "/a~1b".into(),
+                value: json!({"c~d": 1}),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        let change = Change::Changed {
+            path: "/a".into(),
+            from: json!("foo"),
+            to: json!({"bar": 1}),
+        };
+        assert_eq!(change.to_string(), r#"~ /a: "foo" -> {"bar":1}"#);
+
+        let change = Change::Added {
+            path: "/b".into(),
+            value: json!(1),
+        };
+        assert_eq!(change.to_string(), "+ /b: 1");
+
+        let change = Change::Removed {
+            path: "/c".into(),
+            value: json!("ä".repeat(100)),
+        };
+        let expected = format!("- /c: \"{}...", "ä".repeat(79));
+        assert_eq!(change.to_string(), expected);
+    }
+
+    #[test]
+    fn test_json_patch() {
+        let from = json!({"a": 1, "b": {"c": 1}, "d": "x"});
+        let to = json!({"a": 2, "b": {"e": null}, "d": "x"});
+
+        assert_eq!(
+            json_patch(&diff(&from, &to)),
+            json!([
+                {"op": "replace", "path": "/a", "value": 2},
+                {"op": "remove", "path": "/b/c"},
+                {"op": "add", "path": "/b/e", "value": null},
+            ])
+        );
+    }
+
+    #[test]
+    fn test_apply_merge_patch() {
+        let mut target = json!({"a": 1, "b": {"c": 1, "d": 2}, "e": [1, 2]});
+        apply_merge_patch(
+            &mut target,
+            &json!({"a": {"f": true}, "b": {"c": null, "g": 3}, "e": [3]}),
+        );
+
+        assert_eq!(
+            target,
+            json!({"a": {"f": true}, "b": {"d": 2, "g": 3}, "e": [3]})
+        );
+    }
+
+    #[test]
+    fn test_apply_merge_patch_replace() {
+        let mut target = json!({"a": 1});
+        apply_merge_patch(&mut target, &json!("value"));
+        assert_eq!(target, json!("value"));
+
+        let mut target = json!("value");
+        apply_merge_patch(&mut target, &json!({"a": null, "b": 1}));
+        assert_eq!(target, json!({"b": 1}));
+    }
+}
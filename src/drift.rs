@@ -0,0 +1,182 @@
+use crate::client::{Notification, Subscription, TwinClient};
+use drogue_doppelgaenger_model::Thing;
+use futures::stream::{BoxStream, StreamExt};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// A thing, which is managed for a device.
+#[derive(Clone, Debug)]
+struct Tracked {
+    application: String,
+    device: String,
+    /// Fingerprint of the operator-owned sections, as last applied
+    fingerprint: String,
+}
+
+/// Tracks the operator-owned state of things, to detect changes made out-of-band.
+#[derive(Clone)]
+pub struct DriftTracker {
+    /// Tracked things, by twin application and thing name
+    things: Arc<Mutex<HashMap<(String, String), Tracked>>>,
+    fingerprint: fn(&Thing) -> String,
+}
+
+impl DriftTracker {
+    /// Create a new tracker, using the provided function to fingerprint the operator-owned
+    /// sections of a thing.
+    pub fn new(fingerprint: fn(&Thing) -> String) -> Self {
+        Self {
+            things: Default::default(),
+            fingerprint,
+        }
+    }
+
+    /// Record the state of a thing, as applied for a device.
+    pub fn track(&self, application: &str, device: &str, thing: &Thing) {
+        self.things.lock().unwrap().insert(
+            (
+                thing.metadata.application.clone(),
+                thing.metadata.name.clone(),
+            ),
+            Tracked {
+                application: application.to_string(),
+                device: device.to_string(),
+                fingerprint: (self.fingerprint)(thing),
+            },
+        );
+    }
+
+    /// Stop tracking a thing, e.g. before deleting it.
+    pub fn forget(&self, twin_application: &str, thing: &str) {
+        self.things
+            .lock()
+            .unwrap()
+            .remove(&(twin_application.to_string(), thing.to_string()));
+    }
+
+    /// Evaluate a notification, returning the device to reconcile (as application and device
+    /// name), if any.
+    ///
+    /// Deleted things, which aren't tracked, are mapped to their device using `deleted`.
+    fn check<F>(&self, notification: Notification, deleted: &F) -> Option<(String, String)>
+    where
+        F: Fn(&str) -> Option<(String, String)>,
+    {
+        let mut things = self.things.lock().unwrap();
+
+        match notification {
+            Notification::Initial { thing } | Notification::Change { thing } => {
+                let key = (
+                    thing.metadata.application.clone(),
+                    thing.metadata.name.clone(),
+                );
+                let tracked = things.get(&key)?;
+                if tracked.fingerprint == (self.fingerprint)(&thing) {
+                    return None;
+                }
+                log::info!("Managed thing was modified: {}", thing.metadata.name);
+                Some((tracked.application.clone(), tracked.device.clone()))
+            }
+            Notification::Deleted {
+                application: twin_application,
+                thing,
+            } => {
+                let device = match things.remove(&(twin_application, thing.clone())) {
+                    Some(tracked) => (tracked.application, tracked.device),
+                    None => deleted(&thing)?,
+                };
+                log::info!("Managed thing was deleted: {thing}");
+                Some(device)
+            }
+            Notification::Lag { lag } => {
+                log::warn!("Missed {lag} thing notifications, relying on periodic reconcile");
+                None
+            }
+        }
+    }
+
+    /// Watch the things of a twin application, emitting the devices (as application and device
+    /// name), whose things need to be reconciled.
+    ///
+    /// Deleted things, which aren't tracked, are mapped to their device using `deleted`.
+    pub fn watch<F>(
+        &self,
+        client: &TwinClient,
+        twin_application: &str,
+        deleted: F,
+    ) -> BoxStream<'static, (String, String)>
+    where
+        F: Fn(&str) -> Option<(String, String)> + Send + 'static,
+    {
+        let tracker = self.clone();
+
+        client
+            .subscribe(Subscription::Application(twin_application.to_string()))
+            .filter_map(move |notification| {
+                let device = tracker.check(notification, &deleted);
+                async move { device }
+            })
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fingerprint(thing: &Thing) -> String {
+        format!("{:?}", thing.metadata.annotations)
+    }
+
+    fn deleted(thing: &str) -> Option<(String, String)> {
+        thing
+            .strip_suffix("/sensor")
+            .map(|device| ("registry".to_string(), device.to_string()))
+    }
+
+    #[test]
+    fn test_check_change() {
+        let tracker = DriftTracker::new(fingerprint);
+        let mut thing = Thing::new("app", "dev/sensor");
+        tracker.track("registry", "dev", &thing);
+
+        let change = |thing: &Thing| Notification::Change {
+            thing: Box::new(thing.clone()),
+        };
+        assert_eq!(tracker.check(change(&thing), &deleted), None);
+
+        thing
+            .metadata
+            .annotations
+            .insert("foo".to_string(), "bar".to_string());
+        assert_eq!(
+            tracker.check(change(&thing), &deleted),
+            Some(("registry".to_string(), "dev".to_string()))
+        );
+
+        // changes of things which aren't tracked can't be evaluated
+        let other = Thing::new("app", "other/sensor");
+        assert_eq!(tracker.check(change(&other), &deleted), None);
+    }
+
+    #[test]
+    fn test_check_deleted() {
+        let tracker = DriftTracker::new(fingerprint);
+        tracker.track("tracked", "dev", &Thing::new("app", "dev/sensor"));
+
+        let delete = |thing: &str| Notification::Deleted {
+            application: "app".to_string(),
+            thing: thing.to_string(),
+        };
+        assert_eq!(
+            tracker.check(delete("dev/sensor"), &deleted),
+            Some(("tracked".to_string(), "dev".to_string()))
+        );
+        // no longer tracked, so the fallback is used
+        assert_eq!(
+            tracker.check(delete("dev/sensor"), &deleted),
+            Some(("registry".to_string(), "dev".to_string()))
+        );
+        assert_eq!(tracker.check(delete("dev/other"), &deleted), None);
+    }
+}
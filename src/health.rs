@@ -0,0 +1,42 @@
+use async_trait::async_trait;
+use drogue_bazaar::health::{HealthCheckError, HealthChecked};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Readiness of the operator.
+///
+/// The operator is considered ready when it is connected to the MQTT endpoint, and completed its
+/// first full pass of reconciling devices.
+#[derive(Clone)]
+pub struct OperatorHealth {
+    connected: watch::Receiver<bool>,
+    synced: Arc<AtomicBool>,
+}
+
+impl OperatorHealth {
+    pub fn new(connected: watch::Receiver<bool>) -> Self {
+        Self {
+            connected,
+            synced: Default::default(),
+        }
+    }
+
+    /// Mark the first full reconciliation pass as done.
+    pub fn set_synced(&self) {
+        self.synced.store(true, Ordering::Relaxed);
+    }
+}
+
+#[async_trait]
+impl HealthChecked for OperatorHealth {
+    async fn is_ready(&self) -> Result<(), HealthCheckError> {
+        if !*self.connected.borrow() {
+            HealthCheckError::nok("Not connected to MQTT endpoint")
+        } else if !self.synced.load(Ordering::Relaxed) {
+            HealthCheckError::nok("Initial reconciliation not yet completed")
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -1,16 +1,33 @@
-mod client;
+pub mod client;
 mod config;
+mod connection;
+mod dead_letter;
+mod diff;
+mod drift;
+mod health;
+mod metrics;
+mod naming;
 mod operator;
+mod queue;
 mod reconciler;
+mod retry;
+mod selector;
+mod shutdown;
 mod twin;
+mod variables;
 
 pub use operator::*;
 
+use crate::connection::{Connection, ReconnectConfig};
+use crate::dead_letter::DeadLetterConfig;
+use crate::retry::RetryConfig;
+use crate::shutdown::Shutdown;
 use crate::twin::{TwinConfig, TwinReconciler};
 use anyhow::Context;
 use drogue_bazaar::app::{Startup, StartupExt};
 use drogue_client::openid::AccessTokenProvider;
 use paho_mqtt as mqtt;
+use std::path::PathBuf;
 use std::time::Duration;
 
 #[derive(Clone, Debug, serde::Deserialize)]
@@ -28,11 +45,28 @@ pub struct OperatorConfig {
     #[serde(default)]
     mqtt_group_id: Option<String>,
 
+    /// Mqtt client id (defaults to "twin-operator")
+    #[serde(default)]
+    mqtt_client_id: Option<String>,
+
+    /// Suffix appended to the client id (defaults to the hostname, or a random value unless using a persistent session)
+    #[serde(default)]
+    mqtt_client_id_suffix: Option<String>,
+
+    /// Keep the session when disconnecting, to receive the events missed in the meantime
+    #[serde(default)]
+    mqtt_persistent_session: bool,
+
+    /// Path for persisting in-flight messages (defaults to the working directory when using a persistent session)
+    #[serde(default)]
+    mqtt_persistence_path: Option<PathBuf>,
+
     /// API URL
     api: String,
 
-    /// Name of specific application to manage firmware updates for (will use all accessible from service account by default)
-    application: String,
+    /// Name of specific application to manage twins for (will use all accessible from service account by default)
+    #[serde(default)]
+    application: Option<String>,
 
     /// Token for authenticating to Drogue IoT
     token: String,
@@ -54,6 +88,34 @@ pub struct OperatorConfig {
     /// Interval reconciling devices
     #[serde(default, with = "humantime_serde")]
     interval: Option<Duration>,
+
+    /// Number of devices to reconcile in parallel
+    #[serde(default)]
+    workers: Option<usize>,
+
+    /// Backoff for reconnecting to the MQTT endpoint
+    #[serde(default)]
+    reconnect: ReconnectConfig,
+
+    /// Retry policy for failed reconciliations
+    #[serde(default)]
+    retry: RetryConfig,
+
+    /// Where to send events which failed processing
+    #[serde(default)]
+    dead_letter: DeadLetterConfig,
+
+    /// Force a full reconciliation every n-th interval, otherwise unchanged devices are skipped
+    #[serde(default)]
+    resync_every: Option<u32>,
+
+    /// Time to wait for in-flight reconciliations when shutting down
+    #[serde(default, with = "humantime_serde")]
+    shutdown_timeout: Option<Duration>,
+
+    /// Interval for removing things of devices which no longer exist
+    #[serde(default, with = "humantime_serde")]
+    gc_interval: Option<Duration>,
 }
 
 pub async fn run(config: Config, startup: &mut dyn Startup) -> anyhow::Result<()> {
@@ -63,11 +125,24 @@ pub async fn run(config: Config, startup: &mut dyn Startup) -> anyhow::Result<()
     let config = config.operator;
 
     let mqtt_uri = config.mqtt_uri;
+    let client_id = client_id(
+        config.mqtt_client_id,
+        config.mqtt_client_id_suffix,
+        std::env::var("HOSTNAME").ok(),
+        config.mqtt_persistent_session,
+    )?;
+    log::info!("MQTT client id: {client_id}");
+
+    let persistence = match config.mqtt_persistence_path {
+        Some(path) => mqtt::PersistenceType::FilePath(path),
+        None if config.mqtt_persistent_session => mqtt::PersistenceType::File,
+        None => mqtt::PersistenceType::None,
+    };
 
     let mqtt_opts = mqtt::CreateOptionsBuilder::new()
         .server_uri(mqtt_uri)
-        .client_id("twin-operator")
-        .persistence(mqtt::PersistenceType::None)
+        .client_id(client_id)
+        .persistence(persistence)
         .finalize();
     let mqtt_client = mqtt::AsyncClient::new(mqtt_opts)?;
 
@@ -83,7 +158,7 @@ pub async fn run(config: Config, startup: &mut dyn Startup) -> anyhow::Result<()
     conn_opts.user_name(config.user);
     conn_opts.password(config.token);
     conn_opts.keep_alive_interval(Duration::from_secs(30));
-    conn_opts.automatic_reconnect(Duration::from_millis(100), Duration::from_secs(5));
+    conn_opts.clean_session(!config.mqtt_persistent_session);
 
     if !config.disable_tls {
         let ca = config
@@ -103,25 +178,9 @@ pub async fn run(config: Config, startup: &mut dyn Startup) -> anyhow::Result<()
 
     let conn_opts = conn_opts.finalize();
 
-    mqtt_client.set_disconnected_callback(|c, _, _| {
-        log::info!("Disconnected");
-        let t = c.reconnect();
-        if let Err(e) = t.wait_for(Duration::from_secs(10)) {
-            log::warn!("Error reconnecting to broker ({:?}), exiting", e);
-            std::process::exit(1);
-        }
-    });
-
-    mqtt_client.set_connection_lost_callback(|c| {
-        log::info!("Connection lost");
-        let t = c.reconnect();
-        if let Err(e) = t.wait_for(Duration::from_secs(10)) {
-            log::warn!("Error reconnecting to broker ({:?}), exiting", e);
-            std::process::exit(1);
-        }
-    });
-
-    mqtt_client
+    // reconnects are handled by the connection supervisor
+    let connection = Connection::new(mqtt_client, config.reconnect);
+    connection
         .connect(conn_opts)
         .await
         .context("Failed to connect to MQTT endpoint")?;
@@ -130,14 +189,78 @@ pub async fn run(config: Config, startup: &mut dyn Startup) -> anyhow::Result<()
 
     let mut app = Operator::new(
         TwinReconciler::new(twin_config, drg.clone()).await?,
-        mqtt_client,
-        config.mqtt_group_id,
-        config.application,
+        connection,
         drg,
-        config.interval.unwrap_or(Duration::from_secs(60)),
+        OperatorOptions {
+            group_id: config.mqtt_group_id,
+            application: config.application,
+            interval: config.interval.unwrap_or(Duration::from_secs(60)),
+            workers: config.workers.unwrap_or(4),
+            retry: config.retry,
+            dead_letter: config.dead_letter,
+            shutdown_timeout: config.shutdown_timeout.unwrap_or(Duration::from_secs(30)),
+            resync_every: config.resync_every.unwrap_or(10),
+            gc_interval: config.gc_interval.unwrap_or(Duration::from_secs(10 * 60)),
+        },
     );
 
-    startup.spawn(async move { app.run().await });
+    startup.check(app.health());
+    let shutdown = Shutdown::on_signal();
+    startup.spawn(async move { app.run(shutdown).await });
 
     Ok(())
 }
+
+/// Build a client id which is unique per instance.
+///
+/// Using the hostname as default suffix keeps the client id stable across restarts of the same
+/// instance (e.g. a pod of a stateful set), which is required for resuming persistent sessions.
+/// Without a hostname, a random suffix is used, unless the session is persistent.
+fn client_id(
+    base: Option<String>,
+    suffix: Option<String>,
+    hostname: Option<String>,
+    persistent_session: bool,
+) -> anyhow::Result<String> {
+    let base = base.unwrap_or_else(|| "twin-operator".to_string());
+    let suffix = match suffix.or(hostname) {
+        Some(suffix) => suffix,
+        None if persistent_session => anyhow::bail!(
+            "Persistent sessions require a stable client id, set mqtt_client_id_suffix or HOSTNAME"
+        ),
+        None => format!("{:08x}", rand::random::<u32>()),
+    };
+
+    if suffix.is_empty() {
+        Ok(base)
+    } else {
+        Ok(format!("{base}-{suffix}"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_client_id() {
+        let id = |suffix: Option<&str>, hostname: Option<&str>, persistent| {
+            client_id(
+                None,
+                suffix.map(Into::into),
+                hostname.map(Into::into),
+                persistent,
+            )
+        };
+
+        assert_eq!(
+            id(Some("a"), Some("host"), true).unwrap(),
+            "twin-operator-a"
+        );
+        assert_eq!(id(None, Some("host"), true).unwrap(), "twin-operator-host");
+        assert_eq!(id(Some(""), None, true).unwrap(), "twin-operator");
+        assert!(id(None, None, false).unwrap().starts_with("twin-operator-"));
+        // a random suffix would never resume the session
+        assert!(id(None, None, true).is_err());
+    }
+}
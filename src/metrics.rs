@@ -0,0 +1,100 @@
+use crate::reconciler::Outcome;
+use drogue_client::error::ClientError;
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    HistogramVec, IntCounter, IntCounterVec, IntGauge,
+};
+use std::future::Future;
+use std::time::Instant;
+
+lazy_static! {
+    pub static ref EVENTS_RECEIVED: IntCounter = register_int_counter!(
+        "twin_operator_events_received",
+        "Number of events received from the MQTT endpoint"
+    )
+    .unwrap();
+    pub static ref EVENTS_FILTERED: IntCounter = register_int_counter!(
+        "twin_operator_events_filtered",
+        "Number of events which got skipped as not relevant"
+    )
+    .unwrap();
+    pub static ref EVENTS_FAILED: IntCounterVec = register_int_counter_vec!(
+        "twin_operator_events_failed",
+        "Number of events which failed processing",
+        &["reason"]
+    )
+    .unwrap();
+    pub static ref RECONCILE_FAILED: IntCounter = register_int_counter!(
+        "twin_operator_reconcile_failed",
+        "Number of failed device reconciliations"
+    )
+    .unwrap();
+    pub static ref RECONCILE_OUTCOMES: IntCounterVec = register_int_counter_vec!(
+        "twin_operator_reconcile_outcomes",
+        "Outcomes of the reconciliation steps",
+        &["step", "outcome"]
+    )
+    .unwrap();
+    pub static ref TWIN_REQUESTS: HistogramVec = register_histogram_vec!(
+        "twin_operator_twin_requests",
+        "Duration of requests to the twin API, in seconds",
+        &["method", "status"]
+    )
+    .unwrap();
+    pub static ref REGISTRY_REQUESTS: HistogramVec = register_histogram_vec!(
+        "twin_operator_registry_requests",
+        "Duration of requests to the registry API, in seconds",
+        &["operation", "status"]
+    )
+    .unwrap();
+    pub static ref MQTT_CONNECTED: IntGauge = register_int_gauge!(
+        "twin_operator_mqtt_connected",
+        "If the operator is connected to the MQTT endpoint (1) or not (0)"
+    )
+    .unwrap();
+    pub static ref ORPHANED_THINGS: IntCounterVec = register_int_counter_vec!(
+        "twin_operator_orphaned_things",
+        "Number of things found without a device or children, by action taken",
+        &["action"]
+    )
+    .unwrap();
+    pub static ref QUEUE_DEPTH: IntGauge = register_int_gauge!(
+        "twin_operator_queue_depth",
+        "Number of devices waiting to be reconciled"
+    )
+    .unwrap();
+}
+
+/// Run a reconciliation step, recording its outcome.
+pub async fn step<F>(step: &str, f: F) -> anyhow::Result<Outcome>
+where
+    F: Future<Output = anyhow::Result<Outcome>>,
+{
+    let result = f.await;
+    let outcome = match &result {
+        Ok(Outcome::Complete) => "complete",
+        Ok(Outcome::Retry | Outcome::RetryAfter(_)) => "retry",
+        Err(_) => "error",
+    };
+    RECONCILE_OUTCOMES.with_label_values(&[step, outcome]).inc();
+    result
+}
+
+/// Run a registry operation, recording its duration and status.
+pub async fn registry<F, T>(operation: &str, f: F) -> Result<T, ClientError>
+where
+    F: Future<Output = Result<T, ClientError>>,
+{
+    let start = Instant::now();
+    let result = f.await;
+    let status = match &result {
+        Ok(_) => "ok",
+        Err(ClientError::Response(code)) | Err(ClientError::Service { code, .. }) => code.as_str(),
+        Err(_) => "error",
+    };
+    REGISTRY_REQUESTS
+        .with_label_values(&[operation, status])
+        .observe(start.elapsed().as_secs_f64());
+    result
+}
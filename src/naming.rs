@@ -0,0 +1,190 @@
+const DEVICE: &str = "{device}";
+const CHANNEL: &str = "{channel}";
+
+/// A pattern for the name of a thing, using the placeholders `{device}` and `{channel}`.
+///
+/// Values of placeholders must not contain a `/`, so that names can be mapped back to their
+/// device.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct NamePattern(String);
+
+enum Token<'a> {
+    Literal(&'a str),
+    Device,
+    Channel,
+}
+
+impl NamePattern {
+    /// Check if the pattern creates one thing per channel.
+    pub fn has_channel(&self) -> bool {
+        self.0.contains(CHANNEL)
+    }
+
+    /// Render the name of a thing.
+    pub fn render(&self, device: &str, channel: Option<&str>) -> String {
+        self.0
+            .replace(DEVICE, device)
+            .replace(CHANNEL, channel.unwrap_or_default())
+    }
+
+    /// Get the device of a thing name, if it matches the pattern.
+    pub fn device<'n>(&self, name: &'n str) -> Option<&'n str> {
+        let mut device = None;
+        let mut rest = name;
+        let mut tokens = self.tokens().into_iter().peekable();
+
+        while let Some(token) = tokens.next() {
+            match token {
+                Token::Literal(literal) => rest = rest.strip_prefix(literal)?,
+                Token::Device | Token::Channel => {
+                    // a placeholder extends to the next literal, but not beyond a segment
+                    let end = match tokens.peek() {
+                        Some(Token::Literal(literal)) => rest.find(literal)?,
+                        _ => rest.len(),
+                    };
+                    let value = &rest[..end];
+                    if value.is_empty() || value.contains('/') {
+                        return None;
+                    }
+                    if matches!(token, Token::Device) {
+                        device = Some(value);
+                    }
+                    rest = &rest[end..];
+                }
+            }
+        }
+
+        if rest.is_empty() {
+            device
+        } else {
+            None
+        }
+    }
+
+    fn tokens(&self) -> Vec<Token<'_>> {
+        let mut result = vec![];
+        let mut rest = self.0.as_str();
+
+        while !rest.is_empty() {
+            let next = [(DEVICE, Token::Device), (CHANNEL, Token::Channel)]
+                .into_iter()
+                .filter_map(|(placeholder, token)| {
+                    rest.find(placeholder).map(|i| (i, placeholder, token))
+                })
+                .min_by_key(|(i, _, _)| *i);
+
+            match next {
+                Some((i, placeholder, token)) => {
+                    if i > 0 {
+                        result.push(Token::Literal(&rest[..i]));
+                    }
+                    result.push(token);
+                    rest = &rest[i + placeholder.len()..];
+                }
+                None => {
+                    result.push(Token::Literal(rest));
+                    rest = "";
+                }
+            }
+        }
+
+        result
+    }
+}
+
+impl TryFrom<String> for NamePattern {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        if !value.contains(DEVICE) {
+            return Err(format!("name pattern must contain {DEVICE}: {value}"));
+        }
+        if value == DEVICE || value.starts_with('/') {
+            return Err(format!(
+                "name pattern must not conflict with device or group things: {value}"
+            ));
+        }
+
+        let pattern = Self(value);
+        // two adjacent placeholders can't be told apart
+        let adjacent = pattern
+            .tokens()
+            .windows(2)
+            .any(|w| !matches!(w[0], Token::Literal(_)) && !matches!(w[1], Token::Literal(_)));
+        if adjacent {
+            return Err(format!(
+                "placeholders must be separated in name pattern: {}",
+                pattern.0
+            ));
+        }
+
+        Ok(pattern)
+    }
+}
+
+impl From<NamePattern> for String {
+    fn from(pattern: NamePattern) -> Self {
+        pattern.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pattern(pattern: &str) -> NamePattern {
+        NamePattern::try_from(pattern.to_string()).unwrap()
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(pattern("{device}/sensor").render("foo", None), "foo/sensor");
+        assert_eq!(
+            pattern("{device}/{channel}").render("foo", Some("bar")),
+            "foo/bar"
+        );
+        assert!(!pattern("{device}/sensor").has_channel());
+        assert!(pattern("{device}/{channel}").has_channel());
+    }
+
+    #[test]
+    fn test_device() {
+        assert_eq!(pattern("{device}/sensor").device("foo/sensor"), Some("foo"));
+        assert_eq!(pattern("{device}/sensor").device("foo/other"), None);
+        assert_eq!(pattern("{device}/sensor").device("foo/sensor/x"), None);
+        assert_eq!(pattern("{device}/sensor").device("/sensor"), None);
+
+        assert_eq!(pattern("{device}/{channel}").device("foo/bar"), Some("foo"));
+        assert_eq!(pattern("{device}/{channel}").device("foo/"), None);
+        assert_eq!(pattern("{device}/{channel}").device("foo"), None);
+        assert_eq!(pattern("{device}/{channel}").device("foo/bar/baz"), None);
+
+        assert_eq!(
+            pattern("ch-{channel}/{device}").device("ch-1/foo"),
+            Some("foo")
+        );
+        assert_eq!(pattern("{device}-state").device("foo-state"), Some("foo"));
+        assert_eq!(pattern("{device}-state").device("foo/bar-state"), None);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for p in [
+            "{device}/sensor",
+            "{device}/{channel}",
+            "ch-{channel}/{device}",
+        ] {
+            let p = pattern(p);
+            assert_eq!(p.device(&p.render("foo", Some("bar"))), Some("foo"));
+        }
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(NamePattern::try_from("sensor".to_string()).is_err());
+        assert!(NamePattern::try_from("{device}".to_string()).is_err());
+        assert!(NamePattern::try_from("/{device}/sensor".to_string()).is_err());
+        assert!(NamePattern::try_from("{device}{channel}".to_string()).is_err());
+    }
+}
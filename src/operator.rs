@@ -1,23 +1,90 @@
+use crate::connection::Connection;
+use crate::dead_letter::{DeadLetter, DeadLetterConfig, DeadLetterSink};
+use crate::health::OperatorHealth;
+use crate::metrics;
+use crate::queue::WorkQueue;
 use crate::reconciler::{Outcome, Reconciler};
+use crate::retry::RetryConfig;
+use crate::shutdown::Shutdown;
 use cloudevents::{AttributesReader, Event};
 use drogue_client::registry::v1::Device;
+use futures::future::join_all;
 use futures::stream::StreamExt;
 use paho_mqtt as mqtt;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::Mutex;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
 use tokio::time::MissedTickBehavior;
 use tokio::{join, time::Duration};
 
 pub type DrogueClient = drogue_client::registry::v1::Client;
 
+/// Reference to a device, used as key for the work queue.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct DeviceKey {
+    pub application: String,
+    pub device: String,
+}
+
+/// A task watching for drift, shared by the applications of its scope.
+struct Watcher {
+    applications: BTreeSet<String>,
+    handle: JoinHandle<()>,
+}
+
+/// Settings of the operator.
+#[derive(Clone, Debug)]
+pub struct OperatorOptions {
+    /// Mqtt group id for shared subscriptions
+    pub group_id: Option<String>,
+    /// Restrict to a single application, or discover all accessible ones when `None`.
+    pub application: Option<String>,
+    /// Interval for reconciling all devices
+    pub interval: Duration,
+    /// Number of devices to reconcile in parallel
+    pub workers: usize,
+    /// Retry policy for failed reconciliations
+    pub retry: RetryConfig,
+    /// Destinations for failed events
+    pub dead_letter: DeadLetterConfig,
+    /// Time to wait for in-flight reconciliations when shutting down
+    pub shutdown_timeout: Duration,
+    /// Force a full reconciliation of all devices every n-th interval
+    pub resync_every: u32,
+    /// Interval for removing the state of devices, which no longer exist
+    pub gc_interval: Duration,
+}
+
 pub struct Operator<R>
 where
     R: Reconciler,
 {
     reconciler: R,
-    client: mqtt::AsyncClient,
+    connection: Connection,
     group_id: Option<String>,
-    application: String,
+    /// Restrict to a single application, or discover all accessible ones when `None`.
+    application: Option<String>,
+    /// Applications we are currently subscribed to.
+    applications: Mutex<BTreeSet<String>>,
+    /// Tasks watching for drift, per watch scope.
+    watchers: Mutex<HashMap<String, Watcher>>,
     registry: DrogueClient,
     interval: Duration,
+    queue: WorkQueue<DeviceKey>,
+    workers: usize,
+    retry: RetryConfig,
+    /// Number of failed attempts, per device.
+    attempts: Mutex<HashMap<DeviceKey, u32>>,
+    /// Devices which exceeded the maximum number of attempts.
+    retry_later: Mutex<HashSet<DeviceKey>>,
+    dead_letter: DeadLetterSink,
+    health: OperatorHealth,
+    /// Triggers a full reconciliation of all devices.
+    resync: Notify,
+    shutdown_timeout: Duration,
+    resync_every: u32,
+    gc_interval: Duration,
 }
 
 impl<R> Operator<R>
@@ -26,28 +93,167 @@ where
 {
     pub fn new(
         reconciler: R,
-        client: mqtt::AsyncClient,
-        group_id: Option<String>,
-        application: String,
+        connection: Connection,
         registry: DrogueClient,
-        interval: Duration,
+        options: OperatorOptions,
     ) -> Self {
+        let OperatorOptions {
+            group_id,
+            application,
+            interval,
+            workers,
+            retry,
+            dead_letter,
+            shutdown_timeout,
+            resync_every,
+            gc_interval,
+        } = options;
+
         Self {
             reconciler,
-            client,
+            dead_letter: DeadLetterSink::new(dead_letter, connection.client().clone()),
+            health: OperatorHealth::new(connection.state()),
+            resync: Notify::new(),
+            shutdown_timeout,
+            resync_every: resync_every.max(1),
+            gc_interval,
+            connection,
             group_id,
             application,
+            applications: Default::default(),
+            watchers: Default::default(),
             registry,
             interval,
+            queue: Default::default(),
+            workers: workers.max(1),
+            retry,
+            attempts: Default::default(),
+            retry_later: Default::default(),
         }
     }
 
-    pub async fn provision_devices(&self, devices: Vec<Device>) -> anyhow::Result<()> {
+    /// Get a handle to the health state of the operator.
+    pub fn health(&self) -> OperatorHealth {
+        self.health.clone()
+    }
+
+    /// Queue devices for reconciliation.
+    ///
+    /// Unless forced, devices which are already reconciled in their current state are skipped.
+    pub fn provision_devices(&self, devices: Vec<Device>, force: bool) {
         for device in devices {
-            self.handle_changed_device(&device).await?;
+            if !force && self.reconciler.is_reconciled(&device) {
+                log::debug!(
+                    "Skipping unchanged device: {}/{}",
+                    device.metadata.application,
+                    device.metadata.name
+                );
+                continue;
+            }
+
+            self.queue.push(DeviceKey {
+                application: device.metadata.application,
+                device: device.metadata.name,
+            });
         }
+    }
 
-        Ok(())
+    /// Start watching an application for devices the reconciler reports as drifted.
+    fn start_watcher(&self, application: &str) {
+        let scope = self.reconciler.watch_scope(application);
+        let mut watchers = self.watchers.lock().unwrap();
+
+        if let Some(watcher) = watchers.get_mut(&scope) {
+            watcher.applications.insert(application.to_string());
+            return;
+        }
+
+        let mut devices = self.reconciler.watch(&scope);
+        let queue = self.queue.clone();
+        let handle = tokio::spawn(async move {
+            while let Some((application, device)) = devices.next().await {
+                log::info!("Re-applying drifted device: {application}/{device}");
+                queue.push(DeviceKey {
+                    application,
+                    device,
+                });
+            }
+        });
+
+        watchers.insert(
+            scope,
+            Watcher {
+                applications: BTreeSet::from([application.to_string()]),
+                handle,
+            },
+        );
+    }
+
+    /// Stop watching an application, stopping the watch once no application uses it anymore.
+    fn stop_watcher(&self, application: &str) {
+        let scope = self.reconciler.watch_scope(application);
+        let mut watchers = self.watchers.lock().unwrap();
+
+        if let Some(watcher) = watchers.get_mut(&scope) {
+            watcher.applications.remove(application);
+            if watcher.applications.is_empty() {
+                watcher.handle.abort();
+                watchers.remove(&scope);
+            }
+        }
+    }
+
+    /// Discover the applications to manage.
+    ///
+    /// This is either the configured application, or all applications accessible to the
+    /// service account.
+    async fn discover_applications(&self) -> anyhow::Result<BTreeSet<String>> {
+        if let Some(application) = &self.application {
+            return Ok(BTreeSet::from([application.clone()]));
+        }
+
+        Ok(
+            metrics::registry("list_apps", self.registry.list_apps(None))
+                .await?
+                .unwrap_or_default()
+                .into_iter()
+                .map(|app| app.metadata.name)
+                .collect(),
+        )
+    }
+
+    fn topic(&self, application: &str) -> String {
+        match &self.group_id {
+            Some(group_id) => format!("$shared/{group_id}/app/{application}"),
+            None => format!("app/{application}"),
+        }
+    }
+
+    /// Synchronize the MQTT subscriptions with the list of discovered applications.
+    ///
+    /// Returns the applications which are currently managed.
+    pub async fn sync_applications(&self) -> anyhow::Result<Vec<String>> {
+        let discovered = self.discover_applications().await?;
+        let current = self.applications.lock().unwrap().clone();
+
+        for application in discovered.difference(&current) {
+            log::info!("Subscribing to application: {application}");
+            self.connection.subscribe(self.topic(application)).await?;
+            self.start_watcher(application);
+            self.applications
+                .lock()
+                .unwrap()
+                .insert(application.clone());
+        }
+
+        for application in current.difference(&discovered) {
+            log::info!("Unsubscribing from application: {application}");
+            self.connection.unsubscribe(self.topic(application)).await?;
+            self.stop_watcher(application);
+            self.applications.lock().unwrap().remove(application);
+        }
+
+        Ok(discovered.into_iter().collect())
     }
 
     pub async fn reconcile_devices(&self) {
@@ -55,35 +261,133 @@ where
         let mut interval = tokio::time::interval(self.interval);
         interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
-        loop {
-            interval.tick().await;
+        for pass in 0u64.. {
+            let mut force = tokio::select! {
+                _ = interval.tick() => pass % u64::from(self.resync_every) == 0,
+                _ = self.resync.notified() => {
+                    log::info!("Triggered full resync");
+                    interval.reset();
+                    true
+                }
+            };
 
-            let devices = self
-                .registry
-                .list_devices(&self.application, None)
-                .await
-                .unwrap_or(None)
-                .unwrap_or(Vec::new());
+            // give deferred devices another chance
+            let deferred: Vec<_> = self.retry_later.lock().unwrap().drain().collect();
+            for key in deferred {
+                self.queue.push(key);
+            }
+
+            let mut synced = true;
+            let applications = match self.sync_applications().await {
+                Ok(applications) => applications,
+                Err(err) => {
+                    log::warn!("Failed to discover applications: {err}");
+                    synced = false;
+                    self.applications.lock().unwrap().iter().cloned().collect()
+                }
+            };
+
+            // after a restart, only devices which changed in the meantime need to be reconciled
+            if pass == 0 {
+                match self.reconciler.restore(&applications).await {
+                    Ok(()) => force = false,
+                    Err(err) => {
+                        log::warn!("Failed to restore state, reconciling all devices: {err}")
+                    }
+                }
+            }
 
-            self.provision_devices(devices)
+            for application in applications {
+                // forced passes also list devices which no longer match, so they get cleaned up
+                let label_selector = if force {
+                    None
+                } else {
+                    self.reconciler.label_selector()
+                };
+                match metrics::registry(
+                    "list_devices",
+                    self.registry.list_devices(&application, label_selector),
+                )
                 .await
-                .expect("Periodic reconcile failed");
+                {
+                    Ok(devices) => {
+                        let devices = devices.unwrap_or_default();
+                        if let Err(err) = self.reconciler.synced(&application, &devices).await {
+                            log::warn!("Failed to reconcile application '{application}': {err}");
+                            synced = false;
+                        }
+                        self.provision_devices(devices, force);
+                    }
+                    Err(err) => {
+                        log::warn!("Failed to list devices of application '{application}': {err}");
+                        synced = false;
+                    }
+                }
+            }
+
+            if synced {
+                self.health.set_synced();
+            }
         }
     }
 
-    pub async fn run(&mut self) -> Result<(), anyhow::Error> {
-        if let Some(group_id) = &self.group_id {
-            self.client.subscribe(
-                format!("$shared/{}/app/{}", &group_id, &self.application),
-                1,
-            );
-        } else {
-            self.client
-                .subscribe(format!("app/{}", &self.application), 1);
+    /// Periodically remove the state of devices, which no longer exist.
+    ///
+    /// This covers devices which got deleted while the operator wasn't running, or without
+    /// processing the finalizer.
+    pub async fn collect_garbage(&self) {
+        log::info!("Collecting garbage with interval {:?}", self.gc_interval);
+        let mut interval = tokio::time::interval(self.gc_interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        loop {
+            interval.tick().await;
+
+            let applications: Vec<_> = self.applications.lock().unwrap().iter().cloned().collect();
+            if let Err(err) = self.reconciler.collect_garbage(&applications).await {
+                log::warn!("Failed to collect garbage: {err}");
+            }
         }
+    }
+
+    /// Run the operator, until the shutdown gets triggered.
+    pub async fn run(&mut self, shutdown: Shutdown) -> Result<(), anyhow::Error> {
+        let stream = self
+            .connection
+            .take_stream()
+            .ok_or_else(|| anyhow::anyhow!("Operator is already running"))?;
+        self.sync_applications().await?;
+
+        log::info!("Starting {} workers", self.workers);
+        let workers = async {
+            let workers = join_all((0..self.workers).map(|id| self.worker(id, &shutdown)));
+            let deadline = async {
+                shutdown.wait().await;
+                tokio::time::sleep(self.shutdown_timeout).await;
+            };
+            tokio::select! {
+                _ = workers => {}
+                _ = deadline => {
+                    log::warn!("Shutdown deadline exceeded, abandoning in-flight reconciliations");
+                }
+            }
+        };
+
+        join!(
+            shutdown.until(self.connection.supervise(&self.resync)),
+            shutdown.until(self.reconcile_devices()),
+            shutdown.until(self.collect_garbage()),
+            shutdown.until(self.process_events(stream)),
+            workers
+        );
+
+        for (_, watcher) in self.watchers.lock().unwrap().drain() {
+            watcher.handle.abort();
+        }
+
+        log::info!("Disconnecting from MQTT endpoint");
+        self.connection.disconnect().await?;
 
-        let stream = self.client.get_stream(100);
-        join!(self.reconcile_devices(), self.process_events(stream));
         Ok(())
     }
 
@@ -92,70 +396,181 @@ where
         mut stream: paho_mqtt::AsyncReceiver<Option<mqtt::Message>>,
     ) {
         log::info!("Processing events events");
-        loop {
-            if let Some(m) = stream.next().await {
-                if let Some(m) = m {
-                    match serde_json::from_slice::<Event>(m.payload()) {
-                        Ok(e) => {
-                            self.handle_event(e).await.expect("Processing failed");
-                        }
-                        Err(e) => {
-                            log::warn!("Error parsing event: {:?}", e);
-                            break;
-                        }
+        while let Some(m) = stream.next().await {
+            // `None` indicates a disconnect, the client will reconnect
+            if let Some(m) = m {
+                metrics::EVENTS_RECEIVED.inc();
+                match serde_json::from_slice::<Event>(m.payload()) {
+                    Ok(e) => {
+                        let application = m.topic().strip_prefix("app/").map(String::from);
+                        self.handle_event(application, e);
                     }
+                    Err(err) => {
+                        log::warn!(
+                            "Error parsing event (topic: {}, size: {}): {err}",
+                            m.topic(),
+                            m.payload().len()
+                        );
+                        metrics::EVENTS_FAILED.with_label_values(&["parse"]).inc();
+                        self.dead_letter
+                            .send(DeadLetter {
+                                topic: Some(m.topic().to_string()),
+                                payload: Some(base64::encode(m.payload())),
+                                ..DeadLetter::new(format!("failed to parse event: {err}"))
+                            })
+                            .await;
+                    }
+                }
+            }
+        }
+        log::warn!("Event stream closed");
+    }
+
+    /// Process devices from the work queue, until the shutdown gets triggered.
+    async fn worker(&self, id: usize, shutdown: &Shutdown) {
+        log::debug!("Starting worker: {id}");
+        while let Some(key) = shutdown.until(self.queue.pop()).await {
+            log::debug!("Worker {id} processing: {key:?}");
+            self.process(&key, shutdown).await;
+            self.queue.done(&key);
+        }
+        log::debug!("Stopped worker: {id}");
+    }
+
+    async fn process(&self, key: &DeviceKey, shutdown: &Shutdown) {
+        let mut attempt = 0;
+        loop {
+            let (delay, error) = match self.reconcile(key).await {
+                Ok(Outcome::Complete) => {
+                    log::info!("Reconciled device");
+                    self.attempts.lock().unwrap().remove(key);
+                    return;
+                }
+                Ok(Outcome::Retry) => {
+                    log::info!("Need to retry device");
+                    (None, None)
+                }
+                Ok(Outcome::RetryAfter(delay)) => {
+                    log::info!("Need to retry device after {delay:?}");
+                    (Some(delay), None)
+                }
+                Err(err) => {
+                    log::warn!(
+                        "Failed to reconcile device {}/{}: {err:#}",
+                        key.application,
+                        key.device
+                    );
+                    metrics::RECONCILE_FAILED.inc();
+                    (None, Some(err))
                 }
+            };
+
+            if !shutdown.is_triggered() {
+                self.retry(key, delay, error).await;
+                return;
+            }
+
+            // finish the reconciliation now, as the queue is no longer processed
+            attempt += 1;
+            if self.retry.is_exhausted(attempt) {
+                log::warn!("Shutting down, leaving {key:?} for the next start, out of attempts");
+                return;
             }
+            let delay = delay.unwrap_or_else(|| self.retry.delay(attempt));
+            log::info!("Shutting down, retrying {key:?} in {delay:?} (attempt {attempt})");
+            tokio::time::sleep(delay).await;
         }
     }
 
-    async fn handle_missing_device(&self, device: &str) -> anyhow::Result<Outcome> {
-        log::info!("Handle missing device: {device}");
-        self.reconciler.missing(device).await
+    async fn handle_missing_device(
+        &self,
+        application: &str,
+        device: &str,
+    ) -> anyhow::Result<Outcome> {
+        log::info!("Handle missing device: {application}/{device}");
+        self.reconciler.missing(application, device).await
     }
 
     async fn handle_changed_device(&self, device: &Device) -> anyhow::Result<Outcome> {
-        log::info!("Handle changed device: {}", device.metadata.name);
+        log::info!(
+            "Handle changed device: {}/{}",
+            device.metadata.application,
+            device.metadata.name
+        );
         self.reconciler.changed(device).await
     }
 
-    async fn handle_event(&self, event: Event) -> anyhow::Result<()> {
+    fn handle_event(&self, application: Option<String>, event: Event) {
         const REGISTRY_TYPE: &str = "io.drogue.registry.v1";
 
         if event.ty() != REGISTRY_TYPE {
-            return Ok(());
+            metrics::EVENTS_FILTERED.inc();
+            return;
         }
 
+        let application = event
+            .extension("application")
+            .map(|e| e.to_string())
+            .or(application);
         let device = event.extension("device").map(|e| e.to_string());
-        log::info!("Device: {device:?}");
+        log::info!("Application: {application:?}, Device: {device:?}");
 
-        let device = if let Some(device) = device {
-            device
+        if let (Some(application), Some(device)) = (application, device) {
+            self.queue.push(DeviceKey {
+                application,
+                device,
+            });
         } else {
             // missing information, skipping event
-            return Ok(());
+            metrics::EVENTS_FILTERED.inc();
+        }
+    }
+
+    /// Schedule another attempt for a device, or defer it when it ran out of attempts.
+    async fn retry(&self, key: &DeviceKey, delay: Option<Duration>, error: Option<anyhow::Error>) {
+        let attempt = {
+            let mut attempts = self.attempts.lock().unwrap();
+            let attempt = attempts.entry(key.clone()).or_default();
+            *attempt += 1;
+            *attempt
         };
 
-        loop {
-            let outcome =
-                if let Some(device) = self.registry.get_device(&self.application, &device).await? {
-                    self.handle_changed_device(&device).await?
-                } else {
-                    self.handle_missing_device(&device).await?
-                };
+        if self.retry.is_exhausted(attempt) {
+            log::warn!("Deferring {key:?} to next periodic reconcile, out of attempts");
+            self.attempts.lock().unwrap().remove(key);
+            self.retry_later.lock().unwrap().insert(key.clone());
 
-            match outcome {
-                Outcome::Complete => {
-                    log::info!("Reconciled device");
-                    break;
-                }
-                Outcome::Retry => {
-                    log::info!("Need to retry device");
-                    continue;
-                }
-            }
+            let reason = match error {
+                Some(err) => format!("out of attempts, last error: {err:#}"),
+                None => "out of attempts".to_string(),
+            };
+            self.dead_letter
+                .send(DeadLetter {
+                    application: Some(key.application.clone()),
+                    device: Some(key.device.clone()),
+                    ..DeadLetter::new(reason)
+                })
+                .await;
+            return;
         }
 
-        Ok(())
+        let delay = delay.unwrap_or_else(|| self.retry.delay(attempt));
+        log::debug!("Retrying {key:?} in {delay:?} (attempt {attempt})");
+        self.queue.push_after(key.clone(), delay);
+    }
+
+    /// Reconcile a single device, using its current state from the registry.
+    async fn reconcile(&self, key: &DeviceKey) -> anyhow::Result<Outcome> {
+        if let Some(device) = metrics::registry(
+            "get_device",
+            self.registry.get_device(&key.application, &key.device),
+        )
+        .await?
+        {
+            self.handle_changed_device(&device).await
+        } else {
+            self.handle_missing_device(&key.application, &key.device)
+                .await
+        }
     }
 }
@@ -0,0 +1,225 @@
+use crate::metrics;
+use std::collections::{HashSet, VecDeque};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// A work queue, de-duplicating work items by their key.
+///
+/// A key which gets queued multiple times before it gets processed, will only be processed once.
+/// A key which is currently being processed will not be handed out to a second worker, but
+/// re-queued once the current worker reported it as done.
+#[derive(Debug)]
+pub struct WorkQueue<K> {
+    inner: Arc<Inner<K>>,
+}
+
+impl<K> Clone for WorkQueue<K> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Inner<K> {
+    state: Mutex<State<K>>,
+    notify: Notify,
+}
+
+#[derive(Debug)]
+struct State<K> {
+    /// Keys waiting to be processed, in order.
+    queue: VecDeque<K>,
+    /// Keys contained in the queue.
+    pending: HashSet<K>,
+    /// Keys currently being processed.
+    active: HashSet<K>,
+    /// Keys which got queued again while being processed.
+    dirty: HashSet<K>,
+}
+
+impl<K> Default for WorkQueue<K> {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State {
+                    queue: Default::default(),
+                    pending: Default::default(),
+                    active: Default::default(),
+                    dirty: Default::default(),
+                }),
+                notify: Notify::new(),
+            }),
+        }
+    }
+}
+
+impl<K> State<K>
+where
+    K: Clone + Eq + Hash,
+{
+    /// Add a key to the queue, returns `true` if a worker needs to be notified.
+    fn enqueue(&mut self, key: K) -> bool {
+        if self.active.contains(&key) {
+            self.dirty.insert(key);
+            false
+        } else if self.pending.insert(key.clone()) {
+            self.queue.push_back(key);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn next(&mut self) -> Option<K> {
+        let key = self.queue.pop_front()?;
+        self.pending.remove(&key);
+        self.active.insert(key.clone());
+        Some(key)
+    }
+
+    fn update_depth(&self) {
+        metrics::QUEUE_DEPTH.set(self.queue.len() as i64);
+    }
+}
+
+impl<K> WorkQueue<K>
+where
+    K: Clone + Eq + Hash,
+{
+    /// Queue a key for processing.
+    pub fn push(&self, key: K) {
+        let mut state = self.inner.state.lock().unwrap();
+        let notify = state.enqueue(key);
+        state.update_depth();
+        drop(state);
+
+        if notify {
+            self.inner.notify.notify_one();
+        }
+    }
+
+    /// Queue a key for processing, after a delay.
+    pub fn push_after(&self, key: K, delay: Duration)
+    where
+        K: Send + Sync + 'static,
+    {
+        let queue = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            queue.push(key);
+        });
+    }
+
+    /// Wait for the next key to process.
+    ///
+    /// The key must be reported back using [`Self::done`] once it was processed.
+    pub async fn pop(&self) -> K {
+        loop {
+            let notified = self.inner.notify.notified();
+            let next = {
+                let mut state = self.inner.state.lock().unwrap();
+                let next = state.next();
+                state.update_depth();
+                next
+            };
+            if let Some(key) = next {
+                return key;
+            }
+            notified.await;
+        }
+    }
+
+    /// Mark a key as processed.
+    pub fn done(&self, key: &K) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.active.remove(key);
+        let notify = state.dirty.remove(key) && state.enqueue(key.clone());
+        state.update_depth();
+        drop(state);
+
+        if notify {
+            self.inner.notify.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::time::timeout;
+
+    /// Number of keys waiting to be processed.
+    fn waiting<K>(queue: &WorkQueue<K>) -> usize {
+        queue.inner.state.lock().unwrap().queue.len()
+    }
+
+    #[tokio::test]
+    async fn test_dedup_pending() {
+        let queue = WorkQueue::default();
+        queue.push("a");
+        queue.push("b");
+        queue.push("a");
+
+        assert_eq!(waiting(&queue), 2);
+        assert_eq!(queue.pop().await, "a");
+        assert_eq!(queue.pop().await, "b");
+        assert_eq!(waiting(&queue), 0);
+    }
+
+    #[tokio::test]
+    async fn test_requeue_after_done() {
+        let queue = WorkQueue::default();
+        queue.push("a");
+        let key = queue.pop().await;
+
+        // queued again while being processed, twice
+        queue.push("a");
+        queue.push("a");
+        assert_eq!(waiting(&queue), 0);
+
+        queue.done(&key);
+        assert_eq!(waiting(&queue), 1);
+        assert_eq!(queue.pop().await, "a");
+
+        queue.done(&key);
+        assert_eq!(waiting(&queue), 0);
+    }
+
+    #[tokio::test]
+    async fn test_not_handed_out_twice() {
+        let queue = WorkQueue::default();
+        queue.push("a");
+        let key = queue.pop().await;
+
+        queue.push("a");
+        let second = queue.clone();
+        let second = tokio::spawn(async move { second.pop().await });
+
+        // the second worker must wait, until the first one is done
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!second.is_finished());
+
+        queue.done(&key);
+        let key = timeout(Duration::from_secs(5), second)
+            .await
+            .expect("second worker must get the key")
+            .unwrap();
+        assert_eq!(key, "a");
+    }
+
+    #[tokio::test]
+    async fn test_push_after() {
+        let queue = WorkQueue::default();
+        queue.push_after("a", Duration::from_millis(10));
+        assert_eq!(waiting(&queue), 0);
+
+        let key = timeout(Duration::from_secs(5), queue.pop())
+            .await
+            .expect("key must be queued after the delay");
+        assert_eq!(key, "a");
+    }
+}
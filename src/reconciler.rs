@@ -1,13 +1,65 @@
 use async_trait::async_trait;
-use drogue_client::registry::v1::Device;
+use drogue_client::registry::v1::{labels::LabelSelector, Device};
+use futures::stream::{self, BoxStream, StreamExt};
+use std::time::Duration;
 
 pub enum Outcome {
     Complete,
+    /// Retry, using the configured backoff
     Retry,
+    /// Retry after the provided delay
+    RetryAfter(Duration),
 }
 
 #[async_trait]
-pub trait Reconciler {
+pub trait Reconciler: Send + Sync {
     async fn changed(&self, device: &Device) -> anyhow::Result<Outcome>;
-    async fn missing(&self, device: &str) -> anyhow::Result<Outcome>;
+    async fn missing(&self, application: &str, device: &str) -> anyhow::Result<Outcome>;
+
+    /// Check if the current state of the device was already reconciled successfully.
+    fn is_reconciled(&self, _device: &Device) -> bool {
+        false
+    }
+
+    /// Reconcile state shared between devices, after listing the devices of an application.
+    ///
+    /// This gets called once per reconciliation pass, which may only list the devices matching
+    /// the label selector.
+    async fn synced(&self, _application: &str, _devices: &[Device]) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Restore the reconciled state of devices, e.g. from the state they manage.
+    ///
+    /// This gets called before the first reconciliation, which only reconciles devices that
+    /// aren't reconciled, when restoring succeeded.
+    async fn restore(&self, _applications: &[String]) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Selector for listing the devices to reconcile, evaluated by the registry.
+    ///
+    /// Devices which don't match it are still handled when receiving events for them, and
+    /// during forced reconciliations.
+    fn label_selector(&self) -> Option<LabelSelector> {
+        None
+    }
+
+    /// Remove state of devices, which no longer exist in any of the applications.
+    async fn collect_garbage(&self, _applications: &[String]) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Scope of the watch for an application.
+    ///
+    /// Applications with the same scope share a single watch.
+    fn watch_scope(&self, application: &str) -> String {
+        application.to_string()
+    }
+
+    /// Watch for devices (as application and device name), which need to be reconciled outside
+    /// of the regular events, e.g. because the state they manage was changed by someone else.
+    fn watch(&self, _scope: &str) -> BoxStream<'static, (String, String)> {
+        stream::empty().boxed()
+    }
 }
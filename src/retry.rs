@@ -0,0 +1,131 @@
+use std::time::Duration;
+
+/// Policy for retrying the reconciliation of a device.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct RetryConfig {
+    /// Delay before the first retry
+    #[serde(default = "default::initial_delay", with = "humantime_serde")]
+    pub initial_delay: Duration,
+
+    /// Maximum delay between two attempts
+    #[serde(default = "default::max_delay", with = "humantime_serde")]
+    pub max_delay: Duration,
+
+    /// Factor the delay increases by with each attempt
+    #[serde(default = "default::factor")]
+    pub factor: f64,
+
+    /// Fraction of the delay which gets randomized (0.0 to 1.0)
+    #[serde(default = "default::jitter")]
+    pub jitter: f64,
+
+    /// Number of attempts before a device is deferred to the next periodic reconciliation
+    #[serde(default = "default::max_attempts")]
+    pub max_attempts: u32,
+}
+
+mod default {
+    use std::time::Duration;
+
+    pub const fn initial_delay() -> Duration {
+        Duration::from_millis(100)
+    }
+
+    pub const fn max_delay() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    pub const fn factor() -> f64 {
+        2.0
+    }
+
+    pub const fn jitter() -> f64 {
+        0.2
+    }
+
+    pub const fn max_attempts() -> u32 {
+        10
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            initial_delay: default::initial_delay(),
+            max_delay: default::max_delay(),
+            factor: default::factor(),
+            jitter: default::jitter(),
+            max_attempts: default::max_attempts(),
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Calculate the delay for an attempt (starting with 1), using exponential backoff with jitter.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exp = attempt.saturating_sub(1).min(64) as i32;
+        let delay = self.initial_delay.as_secs_f64() * self.factor.max(1.0).powi(exp);
+        let delay = delay.min(self.max_delay.as_secs_f64());
+
+        let jitter = self.jitter.clamp(0.0, 1.0) * rand::random::<f64>();
+        Duration::from_secs_f64(delay * (1.0 - jitter))
+    }
+
+    /// Check if an attempt (starting with 1) exceeds the maximum number of attempts.
+    pub fn is_exhausted(&self, attempt: u32) -> bool {
+        attempt > self.max_attempts
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn no_jitter() -> RetryConfig {
+        RetryConfig {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            factor: 2.0,
+            jitter: 0.0,
+            max_attempts: 3,
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let config = no_jitter();
+        assert_eq!(config.delay(1), Duration::from_millis(100));
+        assert_eq!(config.delay(2), Duration::from_millis(200));
+        assert_eq!(config.delay(3), Duration::from_millis(400));
+        assert_eq!(config.delay(4), Duration::from_millis(800));
+    }
+
+    #[test]
+    fn test_max_delay() {
+        let config = no_jitter();
+        assert_eq!(config.delay(5), Duration::from_secs(1));
+        assert_eq!(config.delay(100), Duration::from_secs(1));
+        assert_eq!(config.delay(u32::MAX), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_jitter() {
+        let config = RetryConfig {
+            jitter: 0.5,
+            ..no_jitter()
+        };
+        for _ in 0..100 {
+            let delay = config.delay(2);
+            assert!(delay <= Duration::from_millis(200), "{delay:?}");
+            assert!(delay >= Duration::from_millis(100), "{delay:?}");
+        }
+    }
+
+    #[test]
+    fn test_exhausted() {
+        let config = no_jitter();
+        assert!(!config.is_exhausted(1));
+        assert!(!config.is_exhausted(3));
+        assert!(config.is_exhausted(4));
+    }
+}
@@ -0,0 +1,297 @@
+use drogue_client::registry::v1::labels::{LabelSelector, Operation};
+use std::collections::HashMap;
+
+/// A label selector, supporting equality and set-based requirements.
+///
+/// It can be parsed from the Kubernetes string syntax, e.g. `env in (prod,staging),!legacy`, or
+/// read from a map of labels, which must be equal.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "RawSelector")]
+pub struct Selector(Vec<Requirement>);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Requirement {
+    Equals(String, String),
+    NotEquals(String, String),
+    In(String, Vec<String>),
+    NotIn(String, Vec<String>),
+    Exists(String),
+    NotExists(String),
+}
+
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum RawSelector {
+    Expression(String),
+    Map(HashMap<String, String>),
+}
+
+impl TryFrom<RawSelector> for Selector {
+    type Error = String;
+
+    fn try_from(value: RawSelector) -> Result<Self, Self::Error> {
+        match value {
+            RawSelector::Expression(expression) => expression.parse(),
+            RawSelector::Map(map) => Ok(Self(
+                map.into_iter()
+                    .map(|(k, v)| Requirement::Equals(k, v))
+                    .collect(),
+            )),
+        }
+    }
+}
+
+impl std::str::FromStr for Selector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        split(s)
+            .into_iter()
+            .map(str::trim)
+            .filter(|term| !term.is_empty())
+            .map(parse_requirement)
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// Split the terms of an expression, ignoring commas inside of value sets.
+fn split(s: &str) -> Vec<&str> {
+    let mut result = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                result.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    result.push(&s[start..]);
+
+    result
+}
+
+fn parse_requirement(term: &str) -> Result<Requirement, String> {
+    if let Some(key) = term.strip_prefix('!') {
+        return Ok(Requirement::NotExists(parse_key(key)?));
+    }
+
+    for (operator, set) in [(" notin ", false), (" in ", true)] {
+        if let Some((key, values)) = term.split_once(operator) {
+            let key = parse_key(key)?;
+            let values = parse_values(values)?;
+            return Ok(if set {
+                Requirement::In(key, values)
+            } else {
+                Requirement::NotIn(key, values)
+            });
+        }
+    }
+
+    if let Some((key, value)) = term.split_once("!=") {
+        return Ok(Requirement::NotEquals(parse_key(key)?, parse_value(value)?));
+    }
+    if let Some((key, value)) = term.split_once("==").or_else(|| term.split_once('=')) {
+        return Ok(Requirement::Equals(parse_key(key)?, parse_value(value)?));
+    }
+
+    Ok(Requirement::Exists(parse_key(term)?))
+}
+
+fn parse_key(key: &str) -> Result<String, String> {
+    let key = key.trim();
+    let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/');
+    if key.is_empty() || !key.chars().all(valid) {
+        return Err(format!("Invalid label key: '{key}'"));
+    }
+    Ok(key.to_string())
+}
+
+fn parse_value(value: &str) -> Result<String, String> {
+    let value = value.trim();
+    let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.');
+    if !value.chars().all(valid) {
+        return Err(format!("Invalid label value: '{value}'"));
+    }
+    Ok(value.to_string())
+}
+
+fn parse_values(values: &str) -> Result<Vec<String>, String> {
+    let values = values
+        .trim()
+        .strip_prefix('(')
+        .and_then(|values| values.strip_suffix(')'))
+        .ok_or_else(|| format!("Values must be enclosed in parentheses: '{values}'"))?;
+
+    values
+        .split(',')
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(parse_value)
+        .collect()
+}
+
+impl Selector {
+    /// Evaluate the selector against a set of labels.
+    pub fn matches(&self, labels: &HashMap<String, String>) -> bool {
+        self.0.iter().all(|requirement| match requirement {
+            Requirement::Equals(k, v) => labels.get(k) == Some(v),
+            Requirement::NotEquals(k, v) => labels.get(k) != Some(v),
+            Requirement::In(k, values) => matches!(labels.get(k), Some(l) if values.contains(l)),
+            Requirement::NotIn(k, values) => {
+                !matches!(labels.get(k), Some(l) if values.contains(l))
+            }
+            Requirement::Exists(k) => labels.contains_key(k),
+            Requirement::NotExists(k) => !labels.contains_key(k),
+        })
+    }
+
+    /// Convert to a selector for the registry, `None` if it would select everything.
+    pub fn to_label_selector(&self) -> Option<LabelSelector> {
+        if self.0.is_empty() {
+            return None;
+        }
+
+        Some(LabelSelector(
+            self.0
+                .iter()
+                .cloned()
+                .map(|requirement| match requirement {
+                    Requirement::Equals(k, v) => Operation::Eq(k, v),
+                    Requirement::NotEquals(k, v) => Operation::NotEq(k, v),
+                    Requirement::In(k, values) => Operation::In(k, values),
+                    Requirement::NotIn(k, values) => Operation::NotIn(k, values),
+                    Requirement::Exists(k) => Operation::Exists(k),
+                    Requirement::NotExists(k) => Operation::NotExists(k),
+                })
+                .collect(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn labels(labels: &[(&str, &str)]) -> HashMap<String, String> {
+        labels
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn selector(s: &str) -> Selector {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            selector("a=1,b==2,c!=3,d in (4,5),e notin (6),f,!g"),
+            Selector(vec![
+                Requirement::Equals("a".into(), "1".into()),
+                Requirement::Equals("b".into(), "2".into()),
+                Requirement::NotEquals("c".into(), "3".into()),
+                Requirement::In("d".into(), vec!["4".into(), "5".into()]),
+                Requirement::NotIn("e".into(), vec!["6".into()]),
+                Requirement::Exists("f".into()),
+                Requirement::NotExists("g".into()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_whitespace() {
+        assert_eq!(
+            selector("  a = 1 ,  d  in  ( 4 , 5 ) , ! g "),
+            Selector(vec![
+                Requirement::Equals("a".into(), "1".into()),
+                Requirement::In("d".into(), vec!["4".into(), "5".into()]),
+                Requirement::NotExists("g".into()),
+            ])
+        );
+        assert_eq!(selector(""), Selector::default());
+        assert_eq!(selector(" , "), Selector::default());
+    }
+
+    #[test]
+    fn test_parse_keys() {
+        assert_eq!(
+            selector("io.drogue/group=a.b_c-d"),
+            Selector(vec![Requirement::Equals(
+                "io.drogue/group".into(),
+                "a.b_c-d".into()
+            )])
+        );
+        assert_eq!(
+            selector("a="),
+            Selector(vec![Requirement::Equals("a".into(), "".into())])
+        );
+    }
+
+    #[test]
+    fn test_parse_malformed() {
+        for s in [
+            "=1",
+            "a b=1",
+            "a=1 2",
+            "a=b=c",
+            "!a=b",
+            "a in 1",
+            "a in (1,2",
+            "a in 1,2)",
+            "a in (1 2)",
+            "a notin",
+            "!",
+        ] {
+            assert!(s.parse::<Selector>().is_err(), "must fail: {s}");
+        }
+    }
+
+    #[test]
+    fn test_matches() {
+        let selector = selector("a=1,b!=2,c in (3,4),d notin (5),e,!f");
+
+        assert!(selector.matches(&labels(&[("a", "1"), ("c", "3"), ("e", "")])));
+        assert!(selector.matches(&labels(&[
+            ("a", "1"),
+            ("b", "1"),
+            ("c", "4"),
+            ("d", "6"),
+            ("e", "x")
+        ])));
+
+        assert!(!selector.matches(&labels(&[("c", "3"), ("e", "")])));
+        assert!(!selector.matches(&labels(&[("a", "1"), ("b", "2"), ("c", "3"), ("e", "")])));
+        assert!(!selector.matches(&labels(&[("a", "1"), ("c", "5"), ("e", "")])));
+        assert!(!selector.matches(&labels(&[("a", "1"), ("d", "5"), ("c", "3"), ("e", "")])));
+        assert!(!selector.matches(&labels(&[("a", "1"), ("c", "3")])));
+        assert!(!selector.matches(&labels(&[("a", "1"), ("c", "3"), ("e", ""), ("f", "")])));
+    }
+
+    #[test]
+    fn test_matches_empty() {
+        assert!(Selector::default().matches(&labels(&[])));
+        assert!(Selector::default().matches(&labels(&[("a", "1")])));
+        assert!(Selector::default().to_label_selector().is_none());
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let expression: Selector = serde_json::from_value(serde_json::json!("a=1,!b")).unwrap();
+        assert!(expression.matches(&labels(&[("a", "1")])));
+        assert!(!expression.matches(&labels(&[("a", "1"), ("b", "")])));
+
+        let map: Selector = serde_json::from_value(serde_json::json!({"a": "1"})).unwrap();
+        assert!(map.matches(&labels(&[("a", "1"), ("b", "2")])));
+        assert!(!map.matches(&labels(&[("a", "2")])));
+
+        assert!(serde_json::from_value::<Selector>(serde_json::json!("a in (")).is_err());
+    }
+}
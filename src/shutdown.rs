@@ -0,0 +1,87 @@
+use std::future::Future;
+use tokio::sync::watch;
+
+/// A signal for shutting down the operator.
+#[derive(Clone, Debug)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    /// Create a shutdown signal, triggered by SIGTERM or Ctrl-C.
+    pub fn on_signal() -> Self {
+        let (tx, rx) = watch::channel(false);
+        tokio::spawn(async move {
+            wait_for_signal().await;
+            log::info!("Shutdown requested");
+            tx.send_replace(true);
+        });
+        Self(rx)
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Wait for the shutdown to be triggered.
+    pub async fn wait(&self) {
+        let mut rx = self.0.clone();
+        loop {
+            let triggered = *rx.borrow_and_update();
+            if triggered || rx.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Run a future until it completes, or the shutdown gets triggered.
+    ///
+    /// Once triggered, the future isn't polled anymore, even if it is ready.
+    pub async fn until<F: Future>(&self, f: F) -> Option<F::Output> {
+        tokio::select! {
+            biased;
+            _ = self.wait() => None,
+            result = f => Some(result),
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut term) => {
+            tokio::select! {
+                _ = term.recv() => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+        }
+        Err(err) => {
+            log::warn!("Failed to register SIGTERM handler: {err}");
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_until() {
+        let (tx, rx) = watch::channel(false);
+        let shutdown = Shutdown(rx);
+
+        assert_eq!(shutdown.until(async { 42 }).await, Some(42));
+
+        tx.send_replace(true);
+        for _ in 0..100 {
+            // a ready future must not win over the shutdown
+            assert_eq!(shutdown.until(async { 42 }).await, None);
+        }
+    }
+}
@@ -1,7 +1,12 @@
 use crate::{
     client::{TwinClient, TwinClientBuilder},
-    config::{load, ThingTemplate},
+    config::{load, SelectableTemplate, Templates, ThingRole, ThingSpec, ThingTemplate},
+    diff::{diff, json_patch, Change},
+    drift::DriftTracker,
+    metrics,
     reconciler::{Outcome, Reconciler},
+    selector::Selector,
+    variables::Variables,
 };
 use anyhow::{anyhow, Context};
 use async_trait::async_trait;
@@ -10,20 +15,59 @@ use drogue_bazaar::auth::openid::TokenConfig;
 use drogue_client::{
     error::ClientError,
     meta::v1::CommonMetadataMut,
-    registry::{self, v1::Device},
+    registry::{
+        self,
+        v1::{labels::LabelSelector, Device},
+    },
 };
 use drogue_doppelgaenger_model::{Changed, Deleting, SyntheticFeature, Thing, Timer};
+use futures::stream::BoxStream;
 use hyper::StatusCode;
 use indexmap::IndexMap;
-use serde_json::Value;
+use serde_json::{json, Value};
 use std::{
-    collections::{btree_map, BTreeMap, HashMap, HashSet},
+    collections::{btree_map, BTreeMap, BTreeSet, HashMap, HashSet},
     path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant},
 };
 use url::Url;
 
 const FINALIZER: &str = "twin";
 
+/// Annotation recording the generation of the device applied to its device thing.
+const ANNOTATION_GENERATION: &str = "io.drogue/device-generation";
+/// Annotation recording the revision of the template applied to a thing.
+const ANNOTATION_TEMPLATE_REVISION: &str = "io.drogue/template-revision";
+/// Annotation recording the entries of a thing owned by the operator.
+const ANNOTATION_MANAGED: &str = "io.drogue/managed";
+/// Annotation assigning a thing to its group.
+const ANNOTATION_GROUP: &str = "io.drogue/group";
+/// Annotation recording the things of a device, on its device thing.
+const ANNOTATION_THINGS: &str = "io.drogue/things";
+
+/// Section of the device spec, configuring its things.
+const SPEC_TWIN: &str = "twin";
+/// Section of the device status, reporting problems with its things.
+const STATUS_TWIN: &str = "twin";
+/// Device annotation, overriding parts of the template (as JSON).
+const ANNOTATION_TEMPLATE_OVERRIDES: &str = "io.drogue/twin-template";
+
+/// Sections of a thing managed by the operator, as JSON pointers.
+const MANAGED_SECTIONS: &[&str] = &[
+    "/metadata/annotations",
+    "/syntheticState",
+    "/reconciliation",
+];
+
+/// Reported state of group things, listing their children.
+const CHILDREN: &str = "$children";
+
+/// Default minimum age of a thing, before it gets garbage collected.
+const DEFAULT_GC_MIN_AGE: Duration = Duration::from_secs(60 * 60);
+/// Default time a group thing has to be empty, before it gets garbage collected.
+const DEFAULT_GROUP_GRACE_PERIOD: Duration = Duration::from_secs(10 * 60);
+
 #[derive(Clone, Debug, serde::Deserialize)]
 pub struct ClientConfig {
     pub url: Url,
@@ -43,16 +87,175 @@ pub struct TwinConfig {
 
 #[derive(Clone, Debug, Default, serde::Deserialize)]
 pub struct ReconcilerConfig {
-    pub application: String,
+    /// Twin application to create things in (defaults to the application of the device)
+    #[serde(default)]
+    pub application: Option<String>,
+    /// Selector for the labels of devices to manage, e.g. `env in (prod,staging),!legacy`
+    #[serde(default)]
+    pub label_selector: Selector,
+    /// Removal of things, whose device no longer exists
+    #[serde(default)]
+    pub garbage_collection: GarbageCollectionConfig,
+    /// Assignment of devices to groups
+    #[serde(default)]
+    pub group: GroupConfig,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct GroupConfig {
+    /// Device label providing the group (defaults to `io.drogue/group`)
+    #[serde(default)]
+    pub label: Option<String>,
+    /// Device annotation providing the group, when the label is missing (defaults to the label)
+    #[serde(default)]
+    pub annotation: Option<String>,
+    /// Group of devices having neither the label nor the annotation
     #[serde(default)]
-    pub label_selector: HashMap<String, String>,
+    pub default: Option<String>,
+    /// Time a group thing has to be without devices, before it is deleted (defaults to 10 minutes)
+    #[serde(default, with = "humantime_serde")]
+    pub grace_period: Option<Duration>,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct GarbageCollectionConfig {
+    /// Only log orphaned things, instead of deleting them
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Minimum age of a thing, before it may be deleted (defaults to one hour)
+    #[serde(default, with = "humantime_serde")]
+    pub min_age: Option<Duration>,
+}
+
+/// Entries of a thing, which are owned by the operator.
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Managed {
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    annotations: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    synthetics: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    changed: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    deleting: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    timers: BTreeSet<String>,
+}
+
+impl Managed {
+    /// Read the managed entries from the annotation of a thing.
+    ///
+    /// Things without the annotation are treated as not having any managed entries, so that
+    /// nothing gets removed from them.
+    fn from_thing(thing: &Thing) -> Self {
+        match thing.metadata.annotations.get(ANNOTATION_MANAGED) {
+            Some(managed) => serde_json::from_str(managed).unwrap_or_else(|err| {
+                log::warn!(
+                    "Invalid managed entries on thing {}, ignoring: {err}",
+                    thing.metadata.name
+                );
+                Default::default()
+            }),
+            None => Default::default(),
+        }
+    }
+
+    fn store(&self, thing: &mut Thing) -> anyhow::Result<()> {
+        thing
+            .metadata
+            .annotations
+            .insert(ANNOTATION_MANAGED.to_string(), serde_json::to_string(self)?);
+        Ok(())
+    }
+
+    /// Fingerprint of the operator-owned sections of a thing.
+    ///
+    /// This only covers the parts the operator configures, so that runtime state (like values or
+    /// logs) doesn't count as a change.
+    fn fingerprint(thing: &Thing) -> String {
+        let managed = Self::from_thing(thing);
+
+        let annotations: BTreeMap<_, _> = managed
+            .annotations
+            .iter()
+            .map(|name| (name, thing.metadata.annotations.get(name)))
+            .collect();
+        let synthetics: BTreeMap<_, _> = managed
+            .synthetics
+            .iter()
+            .map(|name| (name, thing.synthetic_state.get(name).map(|s| &s.r#type)))
+            .collect();
+        let changed: BTreeMap<_, _> = managed
+            .changed
+            .iter()
+            .map(|name| {
+                (
+                    name,
+                    thing.reconciliation.changed.get(name).map(|c| &c.code),
+                )
+            })
+            .collect();
+        let deleting: BTreeMap<_, _> = managed
+            .deleting
+            .iter()
+            .map(|name| {
+                (
+                    name,
+                    thing.reconciliation.deleting.get(name).map(|d| &d.code),
+                )
+            })
+            .collect();
+        let timers: BTreeMap<_, _> = managed
+            .timers
+            .iter()
+            .map(|name| {
+                (
+                    name,
+                    thing
+                        .reconciliation
+                        .timers
+                        .get(name)
+                        .map(|t| json!({"code": t.code, "period": t.period})),
+                )
+            })
+            .collect();
+
+        json!({
+            "group": thing.metadata.annotations.get(ANNOTATION_GROUP),
+            "managed": thing.metadata.annotations.get(ANNOTATION_MANAGED),
+            "annotations": annotations,
+            "synthetics": synthetics,
+            "changed": changed,
+            "deleting": deleting,
+            "timers": timers,
+        })
+        .to_string()
+    }
+}
+
+/// State of a device, which was reconciled successfully.
+///
+/// This is recorded on the device thing, so that it can be restored after a restart.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Observed {
+    generation: u64,
+    /// Revision of the rendered template
+    revision: String,
+    group: Option<String>,
 }
 
 pub struct TwinReconciler {
     client: TwinClient,
     config: ReconcilerConfig,
     registry: registry::v1::Client,
-    template: ThingTemplate,
+    templates: Templates,
+    /// Last reconciled state, by twin application and device name
+    observed: Mutex<HashMap<(String, String), Observed>>,
+    /// Things managed by the operator, to detect drift
+    drift: DriftTracker,
+    /// Group things without devices, and since when, by twin application
+    empty_groups: Mutex<HashMap<String, HashMap<String, Instant>>>,
 }
 
 impl TwinReconciler {
@@ -62,8 +265,20 @@ impl TwinReconciler {
             reconciler: config,
             configuration,
         } = config;
-        let template = load(&configuration).context("loading template configuration")?;
-        log::info!("Thing template: {template:?}");
+        let templates = load(&configuration).context("loading template configuration")?;
+        for template in &templates.templates {
+            log::info!(
+                "Thing template {} ({}): {:?}",
+                template.name,
+                template.revision,
+                template.template
+            );
+        }
+        log::info!(
+            "Group template ({}): {:?}",
+            templates.group_revision(),
+            templates.group
+        );
         let client = TwinClientBuilder::from_url(client.url.clone())
             .client(client.client.clone())
             .token_provider(client.token)
@@ -74,7 +289,10 @@ impl TwinReconciler {
             config,
             client,
             registry,
-            template,
+            templates,
+            observed: Default::default(),
+            drift: DriftTracker::new(Managed::fingerprint),
+            empty_groups: Default::default(),
         })
     }
 }
@@ -82,120 +300,962 @@ impl TwinReconciler {
 #[async_trait]
 impl Reconciler for TwinReconciler {
     async fn changed(&self, device: &Device) -> anyhow::Result<Outcome> {
-        if !self.matches(&device) {
+        if !self.matches(device) {
             log::debug!("Device doesn't match selector");
-            return self.removing(device).await;
+            return metrics::step("remove", self.removing(device)).await;
         }
+        let template = match self.select(device) {
+            Some(template) => template,
+            None => {
+                log::debug!("Device doesn't match any template");
+                return metrics::step("remove", self.removing(device)).await;
+            }
+        };
         if device.metadata.deletion_timestamp.is_some() {
             log::debug!("Device is soft-deleted");
-            return self.removing(device).await;
+            return metrics::step("remove", self.removing(device)).await;
         }
-        self.ensure(&device).await
+        self.ensure(device, template).await
     }
 
-    async fn missing(&self, device: &str) -> anyhow::Result<Outcome> {
-        log::info!("Deleting twin device: {}", device);
+    async fn missing(&self, application: &str, device: &str) -> anyhow::Result<Outcome> {
+        log::info!("Deleting twin device: {}/{}", application, device);
 
-        let thing = Self::sensor_thing(device);
+        let twin_application = self.twin_application(application);
+        self.observed
+            .lock()
+            .unwrap()
+            .remove(&(twin_application.to_string(), device.to_string()));
 
-        // ensure the device is deleted in the twin state
-        match self
+        // the device thing records its things, unless it was never reconciled
+        let things = self
             .client
-            .delete_thing(&self.config.application, &thing)
-            .await
-        {
-            Ok(_) | Err(ClientError::Response(StatusCode::NOT_FOUND)) => Ok(Outcome::Complete),
-            Err(err) => Err(anyhow!(err)),
+            .get_thing(twin_application, device)
+            .await?
+            .and_then(|thing| Self::device_things(&thing))
+            .unwrap_or_else(|| {
+                Self::things(&self.roles(), device, None)
+                    .into_iter()
+                    .map(|(name, _)| name)
+                    .collect()
+            });
+
+        // ensure the things of the device are deleted in the twin state, children first
+        for thing in &things {
+            self.delete_thing(twin_application, thing).await?;
+        }
+        self.delete_thing(twin_application, device).await?;
+
+        Ok(Outcome::Complete)
+    }
+
+    fn is_reconciled(&self, device: &Device) -> bool {
+        let finalizer = device.metadata.finalizers.iter().any(|f| f == FINALIZER);
+
+        // devices we don't manage are reconciled, once they are cleaned up
+        let template = match self.managed_template(device) {
+            Some(template) => template,
+            None => return !finalizer,
+        };
+        if !finalizer {
+            return false;
+        }
+
+        let revision = match Self::overridden_template(device, &template.template) {
+            Ok(overridden) => overridden
+                .as_ref()
+                .unwrap_or(&template.template)
+                .render(&self.variables(device))
+                .map(|template| template.revision()),
+            Err(err) => Err(err),
+        };
+        let revision = match revision {
+            Ok(revision) => revision,
+            Err(_) => return false,
+        };
+
+        let key = (
+            self.twin_application(&device.metadata.application)
+                .to_string(),
+            device.metadata.name.clone(),
+        );
+        self.observed.lock().unwrap().get(&key) == Some(&self.observe(device, revision))
+    }
+
+    async fn synced(&self, application: &str, devices: &[Device]) -> anyhow::Result<()> {
+        // groups are shared between devices, so they get reconciled once per pass
+        let groups: BTreeSet<_> = devices
+            .iter()
+            .filter(|device| self.managed_template(device).is_some())
+            .filter_map(|device| self.group(device))
+            .flat_map(|group| Self::group_things(&group))
+            .collect();
+
+        let twin_application = self.twin_application(application);
+        for name in groups {
+            // groups are shared between devices, so only group variables are available
+            let spec = self
+                .templates
+                .group
+                .render(&Self::group_variables(application, &name))
+                .with_context(|| format!("failed to render group {name}"))?;
+            let ensure = self.ensure_group(twin_application, name, &spec);
+            metrics::step("group", ensure).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn restore(&self, applications: &[String]) -> anyhow::Result<()> {
+        let twin_applications: BTreeSet<_> = applications
+            .iter()
+            .map(|application| self.twin_application(application))
+            .collect();
+
+        // the application of each device, as twin applications may be shared
+        let mut owners = HashMap::new();
+        for application in applications {
+            let devices = metrics::registry(
+                "list_devices",
+                self.registry.list_devices(application, None),
+            )
+            .await?
+            .unwrap_or_default();
+            for device in devices {
+                owners.insert(
+                    (self.twin_application(application), device.metadata.name),
+                    application,
+                );
+            }
+        }
+
+        let roles = self.roles();
+        let mut restored = 0;
+        for twin_application in twin_applications {
+            let things = self.client.list_all_things(twin_application, None).await?;
+            let mut observed = self.observed.lock().unwrap();
+            for thing in &things {
+                let device = match Self::thing_device(&roles, thing) {
+                    Some(device) => device,
+                    None => continue,
+                };
+                // track the things of existing devices, as they are skipped when reconciled
+                if let Some(application) = owners.get(&(twin_application, device.to_string())) {
+                    self.drift.track(application, device, thing);
+                }
+
+                // only device things record the observed state
+                if device != thing.metadata.name {
+                    continue;
+                }
+                if let Some(state) = Self::observed_state(thing) {
+                    observed
+                        .entry((twin_application.to_string(), thing.metadata.name.clone()))
+                        .or_insert(state);
+                    restored += 1;
+                }
+            }
         }
+
+        log::info!("Restored the state of {restored} devices");
+        Ok(())
+    }
+
+    async fn collect_garbage(&self, applications: &[String]) -> anyhow::Result<()> {
+        // collect the existing devices first, so that things created in the meantime are covered
+        // by the minimum age
+        let mut in_use = HashMap::<&str, Option<HashSet<String>>>::new();
+        for application in applications {
+            let twin_application = self.twin_application(application);
+            let devices = metrics::registry(
+                "list_devices",
+                self.registry.list_devices(application, None),
+            )
+            .await?;
+
+            let entry = in_use
+                .entry(twin_application)
+                .or_insert_with(|| Some(HashSet::new()));
+            match (devices, entry) {
+                (Some(devices), Some(in_use)) => {
+                    in_use.extend(devices.into_iter().map(|device| device.metadata.name))
+                }
+                (None, entry) => {
+                    // without the devices, all things of the twin application look orphaned
+                    log::warn!("Application {application} not found, skipping garbage collection of {twin_application}");
+                    *entry = None;
+                }
+                (Some(_), None) => {}
+            }
+        }
+
+        if let Some(twin_application) = &self.config.application {
+            // all applications share the twin application, including ones not managed here
+            let unmanaged: Vec<_> = metrics::registry("list_apps", self.registry.list_apps(None))
+                .await?
+                .unwrap_or_default()
+                .into_iter()
+                .map(|app| app.metadata.name)
+                .filter(|application| !applications.contains(application))
+                .collect();
+            if !unmanaged.is_empty() {
+                log::info!("Applications {unmanaged:?} are not managed, skipping garbage collection of {twin_application}");
+                in_use.insert(twin_application.as_str(), None);
+            }
+        }
+
+        let roles = self.roles();
+        let config = &self.config.garbage_collection;
+        let min_age = chrono::Duration::from_std(config.min_age.unwrap_or(DEFAULT_GC_MIN_AGE))?;
+        let now = Utc::now();
+
+        for (twin_application, in_use) in in_use {
+            let in_use = match in_use {
+                Some(in_use) => in_use,
+                None => continue,
+            };
+            let things = self.client.list_all_things(twin_application, None).await?;
+
+            for thing in &things {
+                let device = match Self::thing_device(&roles, thing) {
+                    Some(device) if !in_use.contains(device) => device,
+                    _ => continue,
+                };
+
+                match thing.metadata.creation_timestamp {
+                    Some(created) if now - created >= min_age => {}
+                    _ => {
+                        log::debug!(
+                            "Orphaned thing too young, skipping: {}",
+                            thing.metadata.name
+                        );
+                        continue;
+                    }
+                }
+
+                if config.dry_run {
+                    log::info!(
+                        "Orphaned thing (dry run): {twin_application}/{} (device: {device})",
+                        thing.metadata.name
+                    );
+                    metrics::ORPHANED_THINGS
+                        .with_label_values(&["dry_run"])
+                        .inc();
+                    continue;
+                }
+
+                log::info!(
+                    "Deleting orphaned thing: {twin_application}/{} (device: {device})",
+                    thing.metadata.name
+                );
+                self.drift.forget(twin_application, &thing.metadata.name);
+                match self
+                    .client
+                    .delete_thing(twin_application, &thing.metadata.name)
+                    .await
+                {
+                    Ok(_) | Err(ClientError::Response(StatusCode::NOT_FOUND)) => {
+                        metrics::ORPHANED_THINGS
+                            .with_label_values(&["deleted"])
+                            .inc();
+                    }
+                    Err(err) => {
+                        log::warn!(
+                            "Failed to delete orphaned thing {}: {err}",
+                            thing.metadata.name
+                        );
+                        metrics::ORPHANED_THINGS
+                            .with_label_values(&["failed"])
+                            .inc();
+                    }
+                }
+            }
+
+            self.collect_groups(twin_application, &things, config.dry_run)
+                .await;
+        }
+
+        Ok(())
+    }
+
+    fn label_selector(&self) -> Option<LabelSelector> {
+        self.config.label_selector.to_label_selector()
+    }
+
+    fn watch_scope(&self, application: &str) -> String {
+        self.twin_application(application).to_string()
+    }
+
+    fn watch(&self, scope: &str) -> BoxStream<'static, (String, String)> {
+        // things which aren't tracked yet are mapped to their device by name, unless several
+        // applications share the twin application
+        let roles = self.roles();
+        let application = match self.config.application {
+            Some(_) => None,
+            None => Some(scope.to_string()),
+        };
+        self.drift.watch(&self.client, scope, move |thing| {
+            let device = Self::name_device(&roles, thing)?;
+            Some((application.clone()?, device.to_string()))
+        })
     }
 }
 
 impl TwinReconciler {
+    /// Get the twin application for a registry application.
+    fn twin_application<'a>(&'a self, application: &'a str) -> &'a str {
+        self.config.application.as_deref().unwrap_or(application)
+    }
+
     fn matches(&self, device: &Device) -> bool {
-        for (k, v) in &self.config.label_selector {
-            match device.metadata.labels.get(k) {
-                Some(l) if l == v => {}
-                _ => {
-                    return false;
+        self.config.label_selector.matches(&device.metadata.labels)
+    }
+
+    /// Select the template for a device, falling back to the default template.
+    fn select(&self, device: &Device) -> Option<&SelectableTemplate> {
+        let templates = &self.templates.templates;
+        templates
+            .iter()
+            .find(|template| template.selector.matches(&device.metadata.labels))
+            .or_else(|| {
+                let default = self.templates.default.as_ref()?;
+                templates.iter().find(|template| &template.name == default)
+            })
+    }
+
+    /// Select the template for a device, if it is managed by the operator.
+    fn managed_template(&self, device: &Device) -> Option<&SelectableTemplate> {
+        if !self.matches(device) || device.metadata.deletion_timestamp.is_some() {
+            return None;
+        }
+        self.select(device)
+    }
+
+    /// The roles of things of all templates.
+    fn roles(&self) -> Vec<ThingRole> {
+        self.templates
+            .templates
+            .iter()
+            .flat_map(|template| template.template.roles())
+            .collect()
+    }
+
+    /// Delete group things without children, once they have been empty for the grace period.
+    async fn collect_groups(&self, application: &str, things: &[Thing], dry_run: bool) {
+        let grace_period = self
+            .config
+            .group
+            .grace_period
+            .unwrap_or(DEFAULT_GROUP_GRACE_PERIOD);
+        let now = Instant::now();
+
+        let expired: Vec<String> = {
+            let mut empty_groups = self.empty_groups.lock().unwrap();
+            let previous = empty_groups.remove(application).unwrap_or_default();
+
+            // only keep groups which are still empty, so that the grace period starts over
+            let empty: HashMap<_, _> = things
+                .iter()
+                .filter(|thing| {
+                    thing.metadata.name.starts_with('/')
+                        && thing.metadata.annotations.contains_key(ANNOTATION_MANAGED)
+                        && !Self::has_children(thing)
+                })
+                .map(|thing| {
+                    let name = &thing.metadata.name;
+                    let since = previous.get(name).copied().unwrap_or(now);
+                    (name.clone(), since)
+                })
+                .collect();
+
+            let expired = empty
+                .iter()
+                .filter(|(_, since)| now.duration_since(**since) >= grace_period)
+                .map(|(name, _)| name.clone())
+                .collect();
+            empty_groups.insert(application.to_string(), empty);
+            expired
+        };
+
+        for group in expired {
+            if dry_run {
+                log::info!("Empty group (dry run): {application}/{group}");
+                metrics::ORPHANED_THINGS
+                    .with_label_values(&["dry_run"])
+                    .inc();
+                continue;
+            }
+
+            log::info!("Deleting empty group: {application}/{group}");
+            match self.client.delete_thing(application, &group).await {
+                Ok(_) | Err(ClientError::Response(StatusCode::NOT_FOUND)) => {
+                    metrics::ORPHANED_THINGS
+                        .with_label_values(&["deleted"])
+                        .inc();
+                }
+                Err(err) => {
+                    log::warn!("Failed to delete empty group {group}: {err}");
+                    metrics::ORPHANED_THINGS
+                        .with_label_values(&["failed"])
+                        .inc();
                 }
             }
         }
+    }
 
-        true
+    /// Check if a group thing has children, as registered by the hierarchy script.
+    fn has_children(thing: &Thing) -> bool {
+        match thing.reported_state.get(CHILDREN) {
+            Some(children) => match &children.value {
+                Value::Object(children) => !children.is_empty(),
+                _ => false,
+            },
+            None => false,
+        }
     }
 
-    fn sensor_thing(device: &str) -> String {
-        format!("{}/sensor", device)
+    /// Get the name of the device owning a thing, based on the naming scheme.
+    ///
+    /// Things are only considered when they are managed by the operator, as a name matching the
+    /// naming scheme could also be chosen by someone else.
+    fn thing_device<'t>(roles: &[ThingRole], thing: &'t Thing) -> Option<&'t str> {
+        if !thing.metadata.annotations.contains_key(ANNOTATION_MANAGED) {
+            return None;
+        }
+        Self::name_device(roles, &thing.metadata.name)
+    }
+
+    /// Get the device of a thing by its name, if it looks like a thing of a device.
+    fn name_device<'n>(roles: &[ThingRole], name: &'n str) -> Option<&'n str> {
+        if name.starts_with('/') {
+            // a group
+            return None;
+        }
+        match roles.iter().find_map(|role| role.name.device(name)) {
+            Some(device) => Some(device),
+            None if !name.contains('/') => Some(name),
+            None => None,
+        }
+    }
+
+    /// Channels of a device, if provided by its spec.
+    fn channels(device: &Device) -> Option<Vec<String>> {
+        let channels = device.spec.get(SPEC_TWIN)?.get("channels")?;
+        match serde_json::from_value(channels.clone()) {
+            Ok(channels) => Some(channels),
+            Err(err) => {
+                log::warn!(
+                    "Invalid channels of device {}, ignoring: {err}",
+                    device.metadata.name
+                );
+                None
+            }
+        }
+    }
+
+    /// Names of the things of a device, with their role.
+    fn things<'r>(
+        roles: &'r [ThingRole],
+        device: &str,
+        channels: Option<&[String]>,
+    ) -> Vec<(String, &'r ThingRole)> {
+        let mut result = vec![];
+        let mut names = HashSet::new();
+        let mut push = |name: String, role: &'r ThingRole| {
+            if names.insert(name.clone()) {
+                result.push((name, role));
+            } else {
+                log::warn!(
+                    "Thing {name} of role '{}' is already created by another role, skipping",
+                    role.role
+                );
+            }
+        };
+
+        for role in roles {
+            if !role.name.has_channel() {
+                push(role.name.render(device, None), role);
+                continue;
+            }
+
+            for channel in channels.unwrap_or(&role.channels) {
+                if channel.is_empty() || channel.contains('/') {
+                    log::warn!("Invalid channel of device {device}, skipping: {channel:?}");
+                    continue;
+                }
+                push(role.name.render(device, Some(channel)), role);
+            }
+        }
+
+        result
+    }
+
+    /// The things of a device, as recorded on its device thing.
+    fn device_things(thing: &Thing) -> Option<BTreeSet<String>> {
+        let things = thing.metadata.annotations.get(ANNOTATION_THINGS)?;
+        match serde_json::from_str(things) {
+            Ok(things) => Some(things),
+            Err(err) => {
+                log::warn!(
+                    "Invalid things on thing {}, ignoring: {err}",
+                    thing.metadata.name
+                );
+                None
+            }
+        }
+    }
+
+    /// Delete a thing, if it still exists.
+    async fn delete_thing(&self, application: &str, thing: &str) -> anyhow::Result<()> {
+        // stop tracking first, so that the deletion isn't reported as drift
+        self.drift.forget(application, thing);
+
+        match self.client.delete_thing(application, thing).await {
+            Ok(_) | Err(ClientError::Response(StatusCode::NOT_FOUND)) => Ok(()),
+            Err(err) => Err(anyhow!(err).context(format!("failed to delete thing {thing}"))),
+        }
+    }
+
+    /// Name of the thing representing a group.
+    fn group_thing(group: &str) -> String {
+        let segments: Vec<_> = group.split('/').filter(|s| !s.is_empty()).collect();
+        format!("/{}", segments.join("/"))
+    }
+
+    /// Names of the things representing a group and its ancestors, starting with the root.
+    fn group_things(group: &str) -> Vec<String> {
+        let segments: Vec<_> = group.split('/').filter(|s| !s.is_empty()).collect();
+        (1..=segments.len())
+            .map(|n| format!("/{}", segments[..n].join("/")))
+            .collect()
+    }
+
+    /// Get the group of a device, from its label or annotation, falling back to the default.
+    fn group(&self, device: &Device) -> Option<String> {
+        let config = &self.config.group;
+        let label = config.label.as_deref().unwrap_or(ANNOTATION_GROUP);
+        let annotation = config.annotation.as_deref().unwrap_or(label);
+
+        device
+            .metadata
+            .labels
+            .get(label)
+            .or_else(|| device.metadata.annotations.get(annotation))
+            .or(config.default.as_ref())
+            .filter(|group| !group.is_empty())
+            .cloned()
+    }
+
+    /// The state of the device, when reconciled with the provided revision of the template.
+    fn observe(&self, device: &Device, revision: String) -> Observed {
+        Observed {
+            generation: device.metadata.generation,
+            revision,
+            group: self.group(device),
+        }
+    }
+
+    /// The state of a device, as recorded on its device thing.
+    fn observed_state(thing: &Thing) -> Option<Observed> {
+        let annotations = &thing.metadata.annotations;
+        Some(Observed {
+            generation: annotations.get(ANNOTATION_GENERATION)?.parse().ok()?,
+            revision: annotations.get(ANNOTATION_TEMPLATE_REVISION)?.clone(),
+            group: annotations.get(ANNOTATION_GROUP).cloned(),
+        })
     }
 
     /// Ensure that the device is provisioned
-    async fn ensure(&self, device: &Device) -> anyhow::Result<Outcome> {
-        log::info!("Ensuring twin device: {}", device.metadata.name);
+    async fn ensure(
+        &self,
+        device: &Device,
+        template: &SelectableTemplate,
+    ) -> anyhow::Result<Outcome> {
+        log::info!(
+            "Ensuring twin device: {} (template: {})",
+            device.metadata.name,
+            template.name
+        );
 
         // ensure that the finalizer is set
 
         let mut device = device.clone();
 
         if device.metadata.ensure_finalizer(FINALIZER) {
-            return match self.registry.update_device(&device).await {
-                Ok(_) => Ok(Outcome::Retry),
-                Err(ClientError::Response(StatusCode::CONFLICT)) => Ok(Outcome::Retry),
-                Err(ClientError::Service {
-                    code: StatusCode::CONFLICT,
-                    ..
-                }) => Ok(Outcome::Retry),
-                Err(err) => Err(anyhow!(err).context("add finalizer")),
-            };
+            return metrics::step("finalizer", async {
+                match metrics::registry("update_device", self.registry.update_device(&device)).await
+                {
+                    Ok(_) => Ok(Outcome::Retry),
+                    Err(ClientError::Response(StatusCode::CONFLICT)) => Ok(Outcome::Retry),
+                    Err(ClientError::Service {
+                        code: StatusCode::CONFLICT,
+                        ..
+                    }) => Ok(Outcome::Retry),
+                    Err(err) => Err(anyhow!(err).context("add finalizer")),
+                }
+            })
+            .await;
+        }
+
+        // apply overrides of the device, reporting invalid ones on the device
+        let overridden = match Self::overridden_template(&device, &template.template) {
+            Ok(overridden) => overridden,
+            Err(err) => {
+                log::warn!(
+                    "Invalid template overrides of device {}: {err:#}",
+                    device.metadata.name
+                );
+                // nothing to do until the device gets fixed
+                return metrics::step(
+                    "status",
+                    self.update_status(&device, Some(("InvalidOverrides", &err))),
+                )
+                .await;
+            }
+        };
+
+        // render the variables of the device, failing the reconciliation on errors
+        let thing_template = match overridden
+            .as_ref()
+            .unwrap_or(&template.template)
+            .render(&self.variables(&device))
+        {
+            Ok(thing_template) => thing_template,
+            Err(err) => {
+                log::warn!(
+                    "Failed to render template for device {}: {err:#}",
+                    device.metadata.name
+                );
+                metrics::step(
+                    "status",
+                    self.update_status(&device, Some(("InvalidVariables", &err))),
+                )
+                .await?;
+                return Err(err.context("render template"));
+            }
+        };
+        let revision = thing_template.revision();
+
+        if let outcome @ (Outcome::Retry | Outcome::RetryAfter(_)) =
+            metrics::step("status", self.update_status(&device, None)).await?
+        {
+            return Ok(outcome);
         }
 
-        // ensure sensor thing
-        if let Outcome::Retry = self.ensure_sensor(&mut device).await? {
-            // retry now
-            return Ok(Outcome::Retry);
+        // ensure the things of the device, before the device thing referencing them
+        let roles = thing_template.roles();
+        let channels = Self::channels(&device);
+        let things = Self::things(&roles, &device.metadata.name, channels.as_deref());
+
+        for (name, role) in &things {
+            let ensure = self.ensure_thing(&device, name.clone(), |thing| {
+                // only the device thing records the generation, so that editing the device
+                // doesn't change all of its things
+                thing.metadata.annotations.remove(ANNOTATION_GENERATION);
+                Self::apply_spec(&revision, &role.spec, thing)
+            });
+            if let outcome @ (Outcome::Retry | Outcome::RetryAfter(_)) =
+                metrics::step(&role.role, ensure).await?
+            {
+                return Ok(outcome);
+            }
         }
 
         // ensure device thing
-        self.ensure_device(&mut device).await
+        let things = things.into_iter().map(|(name, _)| name).collect();
+        let outcome = metrics::step(
+            "device",
+            self.ensure_device(&device, &thing_template, &revision, &things),
+        )
+        .await?;
+
+        if let Outcome::Complete = outcome {
+            self.observed.lock().unwrap().insert(
+                (
+                    self.twin_application(&device.metadata.application)
+                        .to_string(),
+                    device.metadata.name.clone(),
+                ),
+                self.observe(&device, revision),
+            );
+        }
+
+        Ok(outcome)
+    }
+
+    /// Get the template with the overrides of the device applied, if there are any.
+    fn overridden_template(
+        device: &Device,
+        template: &ThingTemplate,
+    ) -> anyhow::Result<Option<ThingTemplate>> {
+        let overrides = match device
+            .spec
+            .get(SPEC_TWIN)
+            .and_then(|twin| twin.get("template"))
+        {
+            Some(overrides) => overrides.clone(),
+            None => match device
+                .metadata
+                .annotations
+                .get(ANNOTATION_TEMPLATE_OVERRIDES)
+            {
+                Some(overrides) => serde_json::from_str(overrides)
+                    .context("failed to parse overrides annotation")?,
+                None => return Ok(None),
+            },
+        };
+
+        template.with_overrides(&overrides).map(Some)
+    }
+
+    /// Variables of a device, available to its templates.
+    fn variables(&self, device: &Device) -> Variables {
+        let mut variables = Variables::default();
+        variables.insert("device", &device.metadata.name);
+        variables.insert("application", &device.metadata.application);
+        if let Some(group) = self.group(device) {
+            variables.insert("group", group);
+        }
+        for (key, value) in &device.metadata.labels {
+            variables.insert(format!("labels.{key}"), value);
+        }
+        for (key, value) in &device.metadata.annotations {
+            variables.insert(format!("annotations.{key}"), value);
+        }
+        variables
+    }
+
+    /// Variables of a group, available to the group template.
+    fn group_variables(application: &str, group: &str) -> Variables {
+        let mut variables = Variables::default();
+        variables.insert("application", application);
+        variables.insert("group", group);
+        variables
+    }
+
+    /// The status section of a device, reporting a problem with its template.
+    fn template_status(error: Option<(&str, &anyhow::Error)>) -> Option<Value> {
+        error.map(|(reason, err)| {
+            json!({
+                "conditions": [{
+                    "type": "TemplateValid",
+                    "status": "False",
+                    "reason": reason,
+                    "message": format!("{err:#}"),
+                }]
+            })
+        })
     }
 
-    async fn ensure_device(&self, device: &Device) -> anyhow::Result<Outcome> {
-        let thing = self
+    /// Report a problem with the template of the device in its status, or clear it.
+    ///
+    /// The device is only updated if the status changed.
+    async fn update_status(
+        &self,
+        device: &Device,
+        error: Option<(&str, &anyhow::Error)>,
+    ) -> anyhow::Result<Outcome> {
+        let status = Self::template_status(error);
+        if device.status.get(STATUS_TWIN) == status.as_ref() {
+            return Ok(Outcome::Complete);
+        }
+
+        let mut device = device.clone();
+        match status {
+            Some(status) => {
+                device.status.insert(STATUS_TWIN.to_string(), status);
+            }
+            None => {
+                device.status.remove(STATUS_TWIN);
+            }
+        }
+
+        match metrics::registry("update_device", self.registry.update_device(&device)).await {
+            Ok(_) => Ok(Outcome::Complete),
+            Err(ClientError::Response(StatusCode::CONFLICT)) => Ok(Outcome::Retry),
+            Err(ClientError::Service {
+                code: StatusCode::CONFLICT,
+                ..
+            }) => Ok(Outcome::Retry),
+            Err(err) => Err(anyhow!(err).context("update status")),
+        }
+    }
+
+    async fn ensure_device(
+        &self,
+        device: &Device,
+        template: &ThingTemplate,
+        revision: &str,
+        things: &BTreeSet<String>,
+    ) -> anyhow::Result<Outcome> {
+        let group = self.group(device);
+        let application = self.twin_application(&device.metadata.application);
+        let current = self
             .client
-            .get_thing(&self.config.application, &device.metadata.name)
+            .get_thing(application, &device.metadata.name)
             .await?;
 
-        match thing {
-            // not created yet, retry
-            // FIXME: possibly delay
-            None => Ok(Outcome::Retry),
-            Some(mut thing) => {
+        // re-parent before updating the thing, so that this gets repeated when failing
+        if let Some(current) = &current {
+            let previous = current.metadata.annotations.get(ANNOTATION_GROUP);
+            self.reparent(application, current, previous, group.as_ref())
+                .await?;
+
+            // delete things the device no longer has, before dropping them from the record
+            for thing in Self::device_things(current)
+                .unwrap_or_default()
+                .difference(things)
+            {
+                log::info!("Deleting thing no longer used by the device: {thing}");
+                self.delete_thing(application, thing).await?;
+            }
+        }
+
+        self.apply_thing(
+            application,
+            device.metadata.name.clone(),
+            current,
+            Some(device),
+            |thing| {
+                Self::apply_spec(revision, &template.device, thing)?;
                 thing.metadata.annotations.insert(
-                    "io.drogue/group".to_string(),
-                    "btmesh/eclipsecon2022".to_string(),
+                    ANNOTATION_GENERATION.to_string(),
+                    device.metadata.generation.to_string(),
                 );
-
-                match self.client.update_thing(thing).await {
-                    Ok(_) => Ok(Outcome::Complete),
-                    Err(ClientError::Response(StatusCode::NOT_FOUND | StatusCode::CONFLICT)) => {
-                        Ok(Outcome::Retry)
+                thing.metadata.annotations.insert(
+                    ANNOTATION_THINGS.to_string(),
+                    serde_json::to_string(things)?,
+                );
+                match &group {
+                    Some(group) => {
+                        thing
+                            .metadata
+                            .annotations
+                            .insert(ANNOTATION_GROUP.to_string(), group.clone());
+                    }
+                    None => {
+                        thing.metadata.annotations.remove(ANNOTATION_GROUP);
                     }
-                    Err(err) => Err(anyhow!(err).context("failed to update device thing")),
                 }
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    /// Ensure that the thing of a group exists, and is configured.
+    ///
+    /// The group annotation is left to the hierarchy script, which uses it to detect if the
+    /// group still needs to be registered with its parent.
+    async fn ensure_group(
+        &self,
+        application: &str,
+        name: String,
+        spec: &ThingSpec,
+    ) -> anyhow::Result<Outcome> {
+        let revision = spec.revision();
+        let thing = self.client.get_thing(application, &name).await?;
+        self.apply_thing(application, name, thing, None, |thing| {
+            Self::apply_spec(&revision, spec, thing)
+        })
+        .await
+    }
+
+    /// Unregister a thing from its previous group, if the group changed.
+    ///
+    /// The hierarchy script registers things with their current parent, but doesn't know the
+    /// previous one, so the operator needs to unregister them from it.
+    async fn reparent(
+        &self,
+        application: &str,
+        thing: &Thing,
+        previous: Option<&String>,
+        group: Option<&String>,
+    ) -> anyhow::Result<()> {
+        let previous = previous.map(|group| Self::group_thing(group));
+        let group = group.map(|group| Self::group_thing(group));
+        if previous == group {
+            return Ok(());
+        }
+
+        let name = &thing.metadata.name;
+        log::info!("Moving thing {name} from group {previous:?} to {group:?}");
+
+        if let Some(previous) = previous {
+            match self
+                .client
+                .send_message(
+                    application,
+                    &previous,
+                    json!({ "unregisterChild": { "$ref": name } }),
+                )
+                .await
+            {
+                Ok(_) | Err(ClientError::Response(StatusCode::NOT_FOUND)) => {}
+                Err(err) => return Err(anyhow!(err).context("unregister from previous group")),
             }
         }
+
+        Ok(())
     }
 
-    async fn ensure_sensor(&self, device: &Device) -> anyhow::Result<Outcome> {
-        let thing = Self::sensor_thing(&device.metadata.name);
-        let thing = self
-            .client
-            .get_thing(&self.config.application, &thing)
-            .await?;
+    /// Ensure that a thing of the device exists, and is configured.
+    async fn ensure_thing<F>(
+        &self,
+        device: &Device,
+        name: String,
+        configure: F,
+    ) -> anyhow::Result<Outcome>
+    where
+        F: Fn(&mut Thing) -> anyhow::Result<()>,
+    {
+        let application = self.twin_application(&device.metadata.application);
+        let thing = self.client.get_thing(application, &name).await?;
+        self.apply_thing(application, name, thing, Some(device), configure)
+            .await
+    }
+
+    /// Update or create a thing, based on its current state.
+    ///
+    /// Things owned by a device are tracked for drift.
+    async fn apply_thing<F>(
+        &self,
+        application: &str,
+        name: String,
+        thing: Option<Thing>,
+        owner: Option<&Device>,
+        configure: F,
+    ) -> anyhow::Result<Outcome>
+    where
+        F: Fn(&mut Thing) -> anyhow::Result<()>,
+    {
+        let track = |thing: &Thing| {
+            if let Some(device) = owner {
+                self.drift
+                    .track(&device.metadata.application, &device.metadata.name, thing);
+            }
+        };
 
         match thing {
             Some(mut thing) => {
-                self.configure_sensor(&mut thing);
-                match self.client.update_thing(thing).await {
+                let current = serde_json::to_value(&thing)?;
+                configure(&mut thing)?;
+                track(&thing);
+
+                let changes = Self::managed_changes(&current, &thing)?;
+                if changes.is_empty() {
+                    return Ok(Outcome::Complete);
+                }
+
+                match self
+                    .client
+                    .patch_thing(
+                        &thing.metadata.application,
+                        &thing.metadata.name,
+                        json_patch(&changes),
+                    )
+                    .await
+                {
                     Ok(_) => Ok(Outcome::Complete),
                     Err(ClientError::Response(StatusCode::CONFLICT | StatusCode::NOT_FOUND)) => {
                         Ok(Outcome::Retry)
@@ -208,11 +1268,9 @@ impl TwinReconciler {
                 }
             }
             None => {
-                let mut thing = Thing::new(
-                    &self.config.application,
-                    Self::sensor_thing(&device.metadata.name),
-                );
-                self.configure_sensor(&mut thing);
+                let mut thing = Thing::new(application, name);
+                configure(&mut thing)?;
+                track(&thing);
 
                 match self.client.create_thing(thing).await {
                     Ok(_) => Ok(Outcome::Complete),
@@ -227,15 +1285,48 @@ impl TwinReconciler {
         }
     }
 
+    /// Compare the sections of a thing managed by the operator, logging the changes.
+    ///
+    /// This leaves the other sections, like the reported state, to the twin service.
+    fn managed_changes(current: &Value, thing: &Thing) -> anyhow::Result<Vec<Change>> {
+        let changes: Vec<_> = diff(current, &serde_json::to_value(thing)?)
+            .into_iter()
+            .filter(|change| is_managed(change.path()))
+            .collect();
+
+        if changes.is_empty() {
+            log::debug!("Thing unchanged, skipping update: {}", thing.metadata.name);
+            return Ok(changes);
+        }
+
+        log::info!(
+            "Updating thing {} ({} changes)",
+            thing.metadata.name,
+            changes.len()
+        );
+        for change in &changes {
+            log::info!("  {change}");
+        }
+
+        Ok(changes)
+    }
+
     /// Remove the device, and remove the finalizer
     async fn removing(&self, device: &Device) -> anyhow::Result<Outcome> {
+        // things only get created after adding the finalizer, so there is nothing to clean up
+        if !device.metadata.finalizers.iter().any(|f| f == FINALIZER) {
+            log::debug!("Device has no finalizer, nothing to remove");
+            return Ok(Outcome::Complete);
+        }
+
         // handle the device as missing (which deletes it in the twin state)
-        self.missing(&device.metadata.name).await?;
+        self.missing(&device.metadata.application, &device.metadata.name)
+            .await?;
 
         // now remove the finalizer
         let mut device = device.clone();
         device.metadata.remove_finalizer(FINALIZER);
-        match self.registry.update_device(&device).await {
+        match metrics::registry("update_device", self.registry.update_device(&device)).await {
             Ok(_) | Err(ClientError::Response(StatusCode::NOT_FOUND)) => {}
             Err(err) => return Err(anyhow!(err).context("remove finalizer")),
         }
@@ -243,10 +1334,29 @@ impl TwinReconciler {
         Ok(Outcome::Complete)
     }
 
-    fn configure_sensor(&self, thing: &mut Thing) {
+    /// Apply the managed content of a template to a thing.
+    fn apply_spec(revision: &str, spec: &ThingSpec, thing: &mut Thing) -> anyhow::Result<()> {
+        let mut managed = Managed::from_thing(thing);
+
         Self::sync_btreemap(
-            &self.template.synthetics,
+            &spec.annotations,
+            &mut thing.metadata.annotations,
+            &mut managed.annotations,
+            |value| value.clone(),
+            |value, current| {
+                current.clone_from(value);
+            },
+        );
+
+        thing.metadata.annotations.insert(
+            ANNOTATION_TEMPLATE_REVISION.to_string(),
+            revision.to_string(),
+        );
+
+        Self::sync_btreemap(
+            &spec.synthetics,
             &mut thing.synthetic_state,
+            &mut managed.synthetics,
             |r#type| SyntheticFeature {
                 r#type: r#type.clone().into(),
                 value: Value::Null,
@@ -258,8 +1368,9 @@ impl TwinReconciler {
         );
 
         Self::sync_indexmap(
-            &self.template.reconciliation.deleting,
+            &spec.reconciliation.deleting,
             &mut thing.reconciliation.deleting,
+            &mut managed.deleting,
             |code| Deleting {
                 code: code.clone().into(),
             },
@@ -269,8 +1380,9 @@ impl TwinReconciler {
         );
 
         Self::sync_indexmap(
-            &self.template.reconciliation.changed,
+            &spec.reconciliation.changed,
             &mut thing.reconciliation.changed,
+            &mut managed.changed,
             |code| Changed {
                 code: code.clone().into(),
                 last_log: Default::default(),
@@ -281,8 +1393,9 @@ impl TwinReconciler {
         );
 
         Self::sync_indexmap(
-            &self.template.reconciliation.timers,
+            &spec.reconciliation.timers,
             &mut thing.reconciliation.timers,
+            &mut managed.timers,
             |timer| Timer {
                 code: timer.code.clone().into(),
                 period: timer.period,
@@ -297,11 +1410,14 @@ impl TwinReconciler {
                 current.period = timer.period;
             },
         );
+
+        managed.store(thing)
     }
 
     fn sync_btreemap<'m, T, R, C, M>(
         config: &IndexMap<String, T>,
         target: &mut BTreeMap<String, R>,
+        managed: &mut BTreeSet<String>,
         creator: C,
         mutator: M,
     ) where
@@ -310,8 +1426,6 @@ impl TwinReconciler {
         C: Fn(&T) -> R,
         M: Fn(&T, &mut R),
     {
-        let mut keys: HashSet<String> = target.keys().map(String::clone).collect();
-
         for (name, value) in config {
             match target.entry(name.clone()) {
                 btree_map::Entry::Vacant(entry) => {
@@ -322,16 +1436,24 @@ impl TwinReconciler {
                 }
             }
 
-            keys.remove(name);
-        }
-        for key in keys {
-            target.remove(&key);
+            managed.insert(name.clone());
         }
+
+        // only remove entries we own, leaving the others alone
+        managed.retain(|key| {
+            if config.contains_key(key) {
+                true
+            } else {
+                target.remove(key);
+                false
+            }
+        });
     }
 
     fn sync_indexmap<'m, T, R, C, M>(
         config: &IndexMap<String, T>,
         target: &mut IndexMap<String, R>,
+        managed: &mut BTreeSet<String>,
         creator: C,
         mutator: M,
     ) where
@@ -340,8 +1462,6 @@ impl TwinReconciler {
         C: Fn(&T) -> R,
         M: Fn(&T, &mut R),
     {
-        let mut keys: HashSet<String> = target.keys().map(String::clone).collect();
-
         for (name, value) in config {
             match target.entry(name.clone()) {
                 indexmap::map::Entry::Vacant(entry) => {
@@ -352,10 +1472,313 @@ impl TwinReconciler {
                 }
             }
 
-            keys.remove(name);
+            managed.insert(name.clone());
         }
-        for key in keys {
-            target.remove(&key);
+
+        // only remove entries we own, leaving the others alone
+        managed.retain(|key| {
+            if config.contains_key(key) {
+                true
+            } else {
+                target.remove(key);
+                false
+            }
+        });
+    }
+}
+
+/// Check if a JSON pointer is part of the sections managed by the operator.
+fn is_managed(path: &str) -> bool {
+    MANAGED_SECTIONS.iter().any(|section| {
+        path.strip_prefix(section)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_managed() {
+        assert!(is_managed("/metadata/annotations"));
+        assert!(is_managed("/metadata/annotations/io.drogue~1group"));
+        assert!(is_managed("/syntheticState/foo"));
+        assert!(is_managed("/reconciliation"));
+
+        assert!(!is_managed("/metadata/annotationsFoo"));
+        assert!(!is_managed("/metadata/labels"));
+        assert!(!is_managed("/reportedState/foo"));
+        assert!(!is_managed("/desiredState"));
+    }
+
+    fn thing(name: &str, managed: bool) -> Thing {
+        let mut thing = Thing::new("app", name);
+        if managed {
+            thing
+                .metadata
+                .annotations
+                .insert(ANNOTATION_MANAGED.to_string(), "[]".to_string());
         }
+        thing
+    }
+
+    #[test]
+    fn test_thing_device() {
+        let roles: Vec<ThingRole> = serde_json::from_value(json!([
+            {"role": "channel", "name": "{device}/{channel}"},
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            TwinReconciler::thing_device(&roles, &thing("foo", true)),
+            Some("foo")
+        );
+        assert_eq!(
+            TwinReconciler::thing_device(&roles, &thing("foo/bar", true)),
+            Some("foo")
+        );
+        assert_eq!(
+            TwinReconciler::thing_device(&roles, &thing("/group", true)),
+            None
+        );
+
+        // not managed by the operator, even when matching the naming scheme
+        assert_eq!(
+            TwinReconciler::thing_device(&roles, &thing("foo", false)),
+            None
+        );
+        // the name alone doesn't tell
+        assert_eq!(TwinReconciler::name_device(&roles, "foo/bar"), Some("foo"));
+        assert_eq!(
+            TwinReconciler::thing_device(&roles, &thing("foo/bar", false)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_observed_state() {
+        let mut thing = thing("foo", true);
+        assert_eq!(TwinReconciler::observed_state(&thing), None);
+
+        let annotations = &mut thing.metadata.annotations;
+        annotations.insert(ANNOTATION_GENERATION.to_string(), "3".to_string());
+        annotations.insert(ANNOTATION_TEMPLATE_REVISION.to_string(), "abc".to_string());
+        assert_eq!(
+            TwinReconciler::observed_state(&thing),
+            Some(Observed {
+                generation: 3,
+                revision: "abc".to_string(),
+                group: None,
+            })
+        );
+
+        let annotations = &mut thing.metadata.annotations;
+        annotations.insert(ANNOTATION_GROUP.to_string(), "a/b".to_string());
+        assert_eq!(
+            TwinReconciler::observed_state(&thing).and_then(|observed| observed.group),
+            Some("a/b".to_string())
+        );
+
+        let annotations = &mut thing.metadata.annotations;
+        annotations.insert(ANNOTATION_GENERATION.to_string(), "x".to_string());
+        assert_eq!(TwinReconciler::observed_state(&thing), None);
+    }
+
+    #[test]
+    fn test_has_children() {
+        let with_children = |children: Value| {
+            let mut thing = thing("/group", true);
+            thing.reported_state.insert(
+                CHILDREN.to_string(),
+                serde_json::from_value(json!({
+                    "lastUpdate": "2022-01-01T00:00:00Z",
+                    "value": children,
+                }))
+                .unwrap(),
+            );
+            thing
+        };
+
+        assert!(!TwinReconciler::has_children(&thing("/group", true)));
+        assert!(!TwinReconciler::has_children(&with_children(json!({}))));
+        assert!(!TwinReconciler::has_children(&with_children(Value::Null)));
+        assert!(TwinReconciler::has_children(&with_children(
+            json!({"device": {}})
+        )));
+    }
+
+    fn template() -> ThingTemplate {
+        serde_json::from_value(json!({
+            "annotations": {"foo": "bar", "baz": "qux"},
+            "device": {
+                "reconciliation": {
+                    "timers": {
+                        "tick": {"code": {"javaScript": "tick();"}, "period": "1m"},
+                    },
+                },
+            },
+        }))
+        .unwrap()
+    }
+
+    fn device(spec: Option<Value>, annotation: Option<&str>) -> Device {
+        let mut device = Device::new("app", "device");
+        if let Some(overrides) = spec {
+            device
+                .spec
+                .insert(SPEC_TWIN.to_string(), json!({ "template": overrides }));
+        }
+        if let Some(overrides) = annotation {
+            device.metadata.annotations.insert(
+                ANNOTATION_TEMPLATE_OVERRIDES.to_string(),
+                overrides.to_string(),
+            );
+        }
+        device
+    }
+
+    #[test]
+    fn test_overrides_none() {
+        let overridden = TwinReconciler::overridden_template(&device(None, None), &template());
+        assert_eq!(overridden.unwrap(), None);
+    }
+
+    #[test]
+    fn test_overrides_round_trip() {
+        // empty overrides must keep the template as it is, including timers-only specs
+        let overridden =
+            TwinReconciler::overridden_template(&device(Some(json!({})), None), &template());
+        assert_eq!(overridden.unwrap(), Some(template()));
+    }
+
+    #[test]
+    fn test_overrides_merge() {
+        let device = device(
+            Some(json!({"annotations": {"foo": "spec", "new": "value"}})),
+            None,
+        );
+        let overridden = TwinReconciler::overridden_template(&device, &template())
+            .unwrap()
+            .unwrap();
+
+        let annotations = &overridden.sensor.annotations;
+        assert_eq!(annotations.get("foo").unwrap(), "spec");
+        assert_eq!(annotations.get("baz").unwrap(), "qux");
+        assert_eq!(annotations.get("new").unwrap(), "value");
+        assert_eq!(overridden.device, template().device);
+    }
+
+    #[test]
+    fn test_overrides_spec_precedence() {
+        let annotation = r#"{"annotations": {"foo": "annotation"}}"#;
+
+        let both = device(
+            Some(json!({"annotations": {"foo": "spec"}})),
+            Some(annotation),
+        );
+        let overridden = TwinReconciler::overridden_template(&both, &template())
+            .unwrap()
+            .unwrap();
+        assert_eq!(overridden.sensor.annotations.get("foo").unwrap(), "spec");
+
+        let only_annotation = device(None, Some(annotation));
+        let overridden = TwinReconciler::overridden_template(&only_annotation, &template())
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            overridden.sensor.annotations.get("foo").unwrap(),
+            "annotation"
+        );
+    }
+
+    #[test]
+    fn test_overrides_null_removes() {
+        let device = device(
+            Some(json!({"annotations": {"foo": null}, "device": null})),
+            None,
+        );
+        let overridden = TwinReconciler::overridden_template(&device, &template())
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(overridden.sensor.annotations.get("foo"), None);
+        assert_eq!(overridden.sensor.annotations.get("baz").unwrap(), "qux");
+        assert!(overridden.device.is_empty());
+    }
+
+    #[test]
+    fn test_overrides_invalid() {
+        for device in [
+            device(None, Some("{not json")),
+            device(Some(json!({"things": "no list"})), None),
+            device(
+                Some(json!({"synthetics": {"foo": {"javaScript": {"path": "/etc/passwd"}}}})),
+                None,
+            ),
+        ] {
+            let err = TwinReconciler::overridden_template(&device, &template()).unwrap_err();
+
+            let status = TwinReconciler::template_status(Some(("InvalidOverrides", &err)))
+                .expect("must report the problem");
+            assert_eq!(status["conditions"][0]["type"], "TemplateValid");
+            assert_eq!(status["conditions"][0]["status"], "False");
+            assert_eq!(status["conditions"][0]["reason"], "InvalidOverrides");
+            assert_eq!(status["conditions"][0]["message"], format!("{err:#}"));
+        }
+    }
+
+    #[test]
+    fn test_template_status_valid() {
+        assert_eq!(TwinReconciler::template_status(None), None);
+    }
+
+    #[test]
+    fn test_group_variables() {
+        let spec: ThingSpec = serde_json::from_value(json!({
+            "synthetics": {
+                "path": {"javaScript": "sendMessage({{ json group }}, {{ json application }});"},
+            },
+        }))
+        .unwrap();
+
+        let variables = TwinReconciler::group_variables("app", "/a/b");
+        let rendered = spec.render(&variables).unwrap();
+        assert_eq!(
+            serde_json::to_value(&rendered).unwrap()["synthetics"]["path"],
+            json!({"javaScript": r#"sendMessage("/a/b", "app");"#})
+        );
+
+        // device variables are not available to groups
+        let spec: ThingSpec = serde_json::from_value(json!({
+            "synthetics": {"device": {"alias": "{{ device }}"}},
+        }))
+        .unwrap();
+        assert!(spec.render(&variables).is_err());
+    }
+
+    #[test]
+    fn test_things_duplicates() {
+        let roles: Vec<ThingRole> = serde_json::from_value(json!([
+            {"role": "sensor", "name": "{device}/sensor"},
+            {"role": "channel", "name": "{device}/{channel}", "channels": ["a"]},
+        ]))
+        .unwrap();
+
+        let things = TwinReconciler::things(&roles, "dev", None);
+        let names: Vec<_> = things
+            .iter()
+            .map(|(name, role)| (name.as_str(), role.role.as_str()))
+            .collect();
+        assert_eq!(names, vec![("dev/sensor", "sensor"), ("dev/a", "channel")]);
+
+        // channels provided by the device may collide with other roles
+        let channels = vec!["sensor".to_string(), "b".to_string()];
+        let things = TwinReconciler::things(&roles, "dev", Some(&channels));
+        let names: Vec<_> = things
+            .iter()
+            .map(|(name, role)| (name.as_str(), role.role.as_str()))
+            .collect();
+        assert_eq!(names, vec![("dev/sensor", "sensor"), ("dev/b", "channel")]);
     }
 }
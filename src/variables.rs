@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+
+const START: &str = "{{";
+const END: &str = "}}";
+const JSON: &str = "json";
+
+/// Variables of a device, which can be used in templates as `{{ name }}`.
+///
+/// `{{ json name }}` inserts the value as a JSON string, and `\{{` a literal `{{`.
+#[derive(Clone, Debug, Default)]
+pub struct Variables(HashMap<String, String>);
+
+impl Variables {
+    pub fn insert(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.0.insert(name.into(), value.into());
+    }
+
+    /// Replace all variables of a text, failing on unknown ones.
+    pub fn render(&self, text: &str) -> anyhow::Result<String> {
+        self.render_with(text, false)
+    }
+
+    /// Replace all variables of a script, failing on unknown ones.
+    ///
+    /// Values which could break the script must be inserted using the `json` form.
+    pub fn render_script(&self, text: &str) -> anyhow::Result<String> {
+        self.render_with(text, true)
+    }
+
+    fn render_with(&self, text: &str, script: bool) -> anyhow::Result<String> {
+        let mut result = String::with_capacity(text.len());
+        let mut rest = text;
+
+        while let Some(start) = rest.find(START) {
+            if rest[..start].ends_with('\\') {
+                result.push_str(&rest[..start - 1]);
+                result.push_str(START);
+                rest = &rest[start + START.len()..];
+                continue;
+            }
+
+            result.push_str(&rest[..start]);
+            rest = &rest[start + START.len()..];
+
+            let end = rest
+                .find(END)
+                .ok_or_else(|| anyhow::anyhow!("Unterminated variable: '{START}{rest}'"))?;
+            let expression = rest[..end].trim();
+            rest = &rest[end + END.len()..];
+
+            let (name, json) = match expression.strip_prefix(JSON) {
+                Some(name) if name.starts_with(char::is_whitespace) => (name.trim_start(), true),
+                _ => (expression, false),
+            };
+            let value = self
+                .0
+                .get(name)
+                .ok_or_else(|| anyhow::anyhow!("Unknown variable: '{name}'"))?;
+
+            if json {
+                result.push_str(&serde_json::to_string(value)?);
+            } else if script && !is_safe(value) {
+                anyhow::bail!(
+                    "Value of variable '{name}' is not safe to use in a script, use '{START} {JSON} {name} {END}' instead"
+                );
+            } else {
+                result.push_str(value);
+            }
+        }
+        result.push_str(rest);
+
+        Ok(result)
+    }
+}
+
+/// Check if a value can be inserted into a script as it is.
+fn is_safe(value: &str) -> bool {
+    value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "_.:/@+-".contains(c))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn variables() -> Variables {
+        let mut variables = Variables::default();
+        variables.insert("device", "foo");
+        variables.insert("labels.owner", "it's \"me\"\n");
+        variables
+    }
+
+    #[test]
+    fn test_render() {
+        let variables = variables();
+        assert_eq!(variables.render("plain").unwrap(), "plain");
+        assert_eq!(variables.render("a {{device}} b").unwrap(), "a foo b");
+        assert_eq!(
+            variables.render("{{ device }}/{{ device }}").unwrap(),
+            "foo/foo"
+        );
+        assert_eq!(
+            variables.render("{{ labels.owner }}").unwrap(),
+            "it's \"me\"\n"
+        );
+    }
+
+    #[test]
+    fn test_render_json() {
+        let variables = variables();
+        assert_eq!(variables.render("{{ json device }}").unwrap(), r#""foo""#);
+        assert_eq!(
+            variables
+                .render_script("let owner = {{ json labels.owner }};")
+                .unwrap(),
+            r#"let owner = "it's \"me\"\n";"#
+        );
+    }
+
+    #[test]
+    fn test_render_escape() {
+        let variables = variables();
+        assert_eq!(
+            variables.render(r"\{{ device }} {{ device }}").unwrap(),
+            "{{ device }} foo"
+        );
+        assert_eq!(variables.render(r"\{{ unknown").unwrap(), "{{ unknown");
+    }
+
+    #[test]
+    fn test_render_script() {
+        let variables = variables();
+        assert_eq!(
+            variables.render_script(r#"send("{{ device }}");"#).unwrap(),
+            r#"send("foo");"#
+        );
+        assert!(variables
+            .render_script(r#"send("{{ labels.owner }}");"#)
+            .is_err());
+    }
+
+    #[test]
+    fn test_render_errors() {
+        let variables = variables();
+        assert!(variables.render("{{ unknown }}").is_err());
+        assert!(variables.render("{{ json unknown }}").is_err());
+        assert!(variables.render("{{ device").is_err());
+        // the json form requires a name
+        assert!(variables.render("{{ json }}").is_err());
+        assert!(variables.render("{{ jsondevice }}").is_err());
+    }
+}